@@ -0,0 +1,119 @@
+//! Compares reading and writing the chunks of a large file from several
+//! workers, once seeking under a shared lock and once with positional I/O.
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    sync::Mutex,
+    thread,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+#[path = "../src/common/pio.rs"]
+mod pio;
+
+const FILE_SIZE: u64 = 64 * 1024 * 1024;
+// The same as client_session::CHUNK_SIZE
+const CHUNK_SIZE: u64 = 32 * 1024;
+const WORKERS: u64 = 8;
+
+/// Gives every worker every `WORKERS`th chunk, like the chunk pipeline does.
+fn worker_offsets(worker: u64) -> impl Iterator<Item = u64> {
+    (0..FILE_SIZE)
+        .step_by(CHUNK_SIZE as usize)
+        .skip(worker as usize)
+        .step_by(WORKERS as usize)
+}
+
+fn read_locked(file: &Mutex<File>) {
+    thread::scope(|scope| {
+        for worker in 0..WORKERS {
+            scope.spawn(move || {
+                let mut chunk = vec![0u8; CHUNK_SIZE as usize];
+                for offset in worker_offsets(worker) {
+                    let mut file = file.lock().unwrap();
+                    file.seek(SeekFrom::Start(offset)).unwrap();
+                    file.read_exact(&mut chunk).unwrap();
+                }
+            });
+        }
+    });
+}
+
+fn read_positional(file: &File) {
+    thread::scope(|scope| {
+        for worker in 0..WORKERS {
+            scope.spawn(move || {
+                let mut chunk = vec![0u8; CHUNK_SIZE as usize];
+                for offset in worker_offsets(worker) {
+                    pio::read_exact_at(file, &mut chunk, offset).unwrap();
+                }
+            });
+        }
+    });
+}
+
+fn write_locked(file: &Mutex<File>) {
+    thread::scope(|scope| {
+        for worker in 0..WORKERS {
+            scope.spawn(move || {
+                let chunk = vec![worker as u8; CHUNK_SIZE as usize];
+                for offset in worker_offsets(worker) {
+                    let mut file = file.lock().unwrap();
+                    file.seek(SeekFrom::Start(offset)).unwrap();
+                    file.write_all(&chunk).unwrap();
+                }
+            });
+        }
+    });
+}
+
+fn write_positional(file: &File) {
+    thread::scope(|scope| {
+        for worker in 0..WORKERS {
+            scope.spawn(move || {
+                let chunk = vec![worker as u8; CHUNK_SIZE as usize];
+                for offset in worker_offsets(worker) {
+                    pio::write_all_at(file, &chunk, offset).unwrap();
+                }
+            });
+        }
+    });
+}
+
+fn chunk_io(c: &mut Criterion) {
+    let source = tempfile::NamedTempFile::new().unwrap();
+    let content: Vec<u8> = (0..FILE_SIZE).map(|i| (i % 253) as u8).collect();
+    std::fs::write(source.path(), content).unwrap();
+    let destination = tempfile::NamedTempFile::new().unwrap();
+    destination.as_file().set_len(FILE_SIZE).unwrap();
+
+    let mut group = c.benchmark_group("chunk_io");
+    group.throughput(Throughput::Bytes(FILE_SIZE));
+    group.sample_size(10);
+
+    let file = File::open(source.path()).unwrap();
+    group.bench_function(BenchmarkId::new("read", "locked"), |b| {
+        let file = Mutex::new(file.try_clone().unwrap());
+        b.iter(|| read_locked(&file))
+    });
+    group.bench_function(BenchmarkId::new("read", "positional"), |b| {
+        b.iter(|| read_positional(&file))
+    });
+
+    let file = File::options()
+        .write(true)
+        .open(destination.path())
+        .unwrap();
+    group.bench_function(BenchmarkId::new("write", "locked"), |b| {
+        let file = Mutex::new(file.try_clone().unwrap());
+        b.iter(|| write_locked(&file))
+    });
+    group.bench_function(BenchmarkId::new("write", "positional"), |b| {
+        b.iter(|| write_positional(&file))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, chunk_io);
+criterion_main!(benches);
@@ -1,84 +1,364 @@
-use clap::Clap;
-use std::path::Path;
+mod progress;
+
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
+use rand::{seq::SliceRandom, Rng};
 use std::io::{self, Write};
-use std::option::Option;
-#[derive(Debug, Clap)]
-#[clap(
-    name = concat!(
-        "NAME: \n   ",
-        env!("CARGO_BIN_NAME"),
-        " - easily and securely transfer stuff from one computer to another\n\n",
-        "VERSION: \n   ",
-        concat!("v", env!("CARGO_PKG_VERSION"), "-", env!("CURRENT_COMMIT_HASH"))
-        
-    ),
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use self::progress::show_progress;
+use crate::{
+    common::{
+        compress::CompressionAlgorithm,
+        config::{Config, OverwriteMode, ReceivePolicy},
+        interaction::AutoAccept,
+    },
+    crypto::hash::HashAlgorithm,
+    proto::FilesInformation,
+    relay::{
+        client::{ping, wait_for_receiver_on_either, RelayClient},
+        limits::RelayLimits,
+        server::Relay,
+    },
+};
+
+const DEFAULT_RELAY: &str = "localhost:9009";
+const DEFAULT_PASSWORD: &str = "pass123";
+const DEFAULT_PORTS: &str = "9009,9010,9011,9012,9013";
+// Kept apart from the default relay ports so a relay on the same machine still works
+const DEFAULT_LOCAL_PORTS: &str = "9019,9020,9021,9022,9023";
+
+const LOCAL_RELAY_START_ATTEMPTS: usize = 20;
+
+// Words used to build human readable codes, in the spirit of croc's mnemonicode.
+const CODE_WORDS: &[&str] = &[
+    "acid", "alpha", "amber", "anchor", "apple", "arrow", "atlas", "autumn", "bamboo", "banjo",
+    "basil", "beacon", "berry", "bison", "blade", "bonus", "cactus", "camel", "canal", "candle",
+    "cargo", "cedar", "cobra", "comet", "copper", "coral", "delta", "denim", "desert", "dingo",
+    "eagle", "echo", "ember", "falcon", "fiber", "flint", "forest", "galaxy", "garlic", "gecko",
+    "ginger", "glacier", "harbor", "hazel", "helium", "igloo", "indigo", "jaguar", "jasmine",
+    "jungle", "kayak", "kiwi", "lagoon", "lemon", "lotus", "magnet", "mango", "maple", "meteor",
+    "nectar", "nickel", "oasis", "olive", "orbit", "panda", "pepper", "pilot", "quartz", "radar",
+    "raven", "ripple", "saddle", "salmon", "sierra", "tango", "thunder", "tulip", "velvet",
+    "violet", "walnut", "willow", "yonder", "zebra", "zephyr",
+];
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "croc",
+    version,
+    about = "easily and securely transfer stuff from one computer to another"
 )]
 struct Opts {
-    /// automatically agree to all prompts (default: false)
-    #[clap(long, short)]
+    /// automatically agree to all prompts
+    #[arg(long, short, global = true)]
     yes: bool,
-    #[clap(subcommand)]
+
+    /// address of the relay
+    #[arg(long, global = true, value_name = "address", default_value = DEFAULT_RELAY)]
+    relay: String,
+
+    /// password for the relay
+    #[arg(long, global = true, value_name = "password", default_value = DEFAULT_PASSWORD)]
+    pass: String,
+
+    /// what to do with received files that already exist (prompt, skip, overwrite, rename)
+    #[arg(long, global = true, value_name = "mode", default_value = "prompt", value_parser = parse_overwrite_mode)]
+    overwrite: OverwriteMode,
+
+    /// refuse transfers bigger than this many bytes
+    #[arg(long, global = true, value_name = "bytes")]
+    max_size: Option<u64>,
+
+    /// folder to put received files in
+    #[arg(long, global = true, value_name = "folder", default_value = ".")]
+    out: PathBuf,
+
+    #[command(subcommand)]
     subcmd: Option<Commands>,
 }
 
-#[derive(Debug, Clap)]
+#[derive(Debug, Subcommand)]
 enum Commands {
+    /// send a file (see options with croc send -h)
     Send(Send),
+    /// receive files using a code
+    Receive(Receive),
+    /// start your own relay (optional)
+    Relay(RelayOpts),
 }
 
-#[derive(Debug, Clap)]
-/// send a file (see options with croc send -h)
+#[derive(Debug, Args)]
 struct Send {
-    #[clap(short, long, value_name = "value")]
     /// codephrase used to connect to relay
+    #[arg(short, long, value_name = "value")]
     code: Option<String>,
 
-    #[clap(long)]
     /// disable local relay when sending
+    #[arg(long)]
     no_local: bool,
 
-    #[clap(long)]
-    /// disable multiplexing
+    /// send everything over a single data connection
+    #[arg(long)]
     no_multi: bool,
 
-    #[clap(long)]
-    /// ports of the local relay (optional) (default: "9009,9010,9011,9012,9013")
-    port: Option<String>,
+    /// ports of the local relay, used by receivers on the same network
+    #[arg(long, value_delimiter = ',', default_value = DEFAULT_LOCAL_PORTS)]
+    local_ports: Vec<u16>,
+
+    /// hash algorithm (sha256, xxhash, imohash)
+    #[arg(long, default_value = "sha256", value_parser = parse_hash_algorithm)]
+    hash: HashAlgorithm,
+
+    /// disable compression
+    #[arg(long)]
+    no_compress: bool,
+
+    /// compression used for file chunks (flate, zstd), only Rust peers know zstd
+    #[arg(long, default_value = "flate", value_parser = parse_compression)]
+    compression: CompressionAlgorithm,
+
+    /// send some text instead of files
+    #[arg(long, value_name = "value", conflicts_with = "file_name")]
+    text: Option<String>,
+
+    /// files or folders to send over the relay
+    #[arg(required_unless_present = "text", value_parser = file_exist)]
+    file_name: Vec<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct Receive {
+    /// codephrase given by the sender
+    code: String,
+}
+
+#[derive(Debug, Args)]
+struct RelayOpts {
+    /// host of the relay
+    #[arg(long, default_value = "0.0.0.0")]
+    host: String,
+
+    /// ports of the relay, the first one is the main port and the rest are used for multiplexing
+    #[arg(long, value_delimiter = ',', default_value = DEFAULT_PORTS)]
+    ports: Vec<u16>,
+
+    /// seconds a room may wait for its receiver, or stay idle, before it is dropped
+    #[arg(long, default_value_t = 3 * 60 * 60, value_parser = clap::value_parser!(u64).range(1..))]
+    room_ttl: u64,
+
+    /// seconds a client may take to get into a room once connected
+    #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    handshake_timeout: u64,
+
+    /// connections the relay handles at once
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// rooms open at once
+    #[arg(long)]
+    max_rooms: Option<usize>,
+
+    /// rooms one IP may have open at once, each data port of a transfer takes one more
+    #[arg(long)]
+    max_rooms_per_ip: Option<usize>,
+
+    /// new handshakes one IP may start every second
+    #[arg(long)]
+    handshakes_per_second: Option<u32>,
+
+    /// bytes the relay passes on every second
+    #[arg(long)]
+    bytes_per_second: Option<u64>,
 
-    #[clap(required = true, validator=file_exist)]
-    /// send a file/files over the relay
-    file_name: Vec<String>,
+    /// bytes one room passes on every second
+    #[arg(long)]
+    room_bytes_per_second: Option<u64>,
+
+    /// bytes passed on every second to and from one IP
+    #[arg(long)]
+    ip_bytes_per_second: Option<u64>,
+
+    /// bytes one room passes on before it is closed
+    #[arg(long)]
+    max_room_bytes: Option<u64>,
+
+    /// address to serve Prometheus metrics on, at /metrics (e.g. 0.0.0.0:9100)
+    #[arg(long)]
+    metrics: Option<String>,
 }
-fn file_exist(val: &str) -> Result<(), io::Error> {
-    if !Path::new(val).exists() {
-        return Err(io::Error::new(io::ErrorKind::NotFound, "File  Not Found!"));
+
+fn file_exist(val: &str) -> Result<PathBuf, io::Error> {
+    let path = PathBuf::from(val);
+    if !path.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "File Not Found!"));
     }
-    Ok(())
+    Ok(path)
+}
+
+fn parse_compression(val: &str) -> Result<CompressionAlgorithm, String> {
+    CompressionAlgorithm::from_str(val).map_err(|err| err.to_string())
+}
+
+fn parse_overwrite_mode(val: &str) -> Result<OverwriteMode, String> {
+    OverwriteMode::from_str(val).map_err(|err| err.to_string())
 }
-pub fn run() -> Result<(), io::Error>{
+
+fn parse_hash_algorithm(val: &str) -> Result<HashAlgorithm, String> {
+    HashAlgorithm::from_str(val).map_err(|err| err.to_string())
+}
+
+pub async fn run() -> Result<()> {
     let opts: Opts = Opts::parse();
+    let mut config = Config::default()
+        .with_policy(ReceivePolicy {
+            auto_accept: opts.yes,
+            overwrite: opts.overwrite,
+            max_size: opts.max_size,
+        })
+        .with_base_dir(opts.out);
+    if opts.yes {
+        config = config.with_interaction(AutoAccept);
+    }
     match opts.subcmd {
         None => {
-            return run_croc();
-        },
-        Some(cmd) => {
-            println!("{:?}", cmd);
-            Ok(())
+            let code = tokio::task::spawn_blocking(ask_for_code).await??;
+            receive(&opts.relay, &opts.pass, &code, config).await
         }
+        Some(Commands::Send(cmd)) => {
+            let config = config.with_multiplex(!cmd.no_multi);
+            send(&opts.relay, &opts.pass, cmd, config).await
+        }
+        Some(Commands::Receive(cmd)) => receive(&opts.relay, &opts.pass, &cmd.code, config).await,
+        Some(Commands::Relay(cmd)) => relay(cmd, opts.pass).await,
     }
 }
 
-fn run_croc() -> Result<(), io::Error>{
+fn ask_for_code() -> Result<String> {
     let mut input = String::new();
     print!("Enter receive code: ");
-    io::stdout().flush().unwrap();
-    match io::stdin().read_line(& mut input){
-        Ok(_) => (),
-        Err(error) => {
-            //panic!("{:?}", error);
-            return Err(error)
+    io::stdout().flush()?;
+    io::stdin().read_line(&mut input)?;
+    Ok(input.trim().to_string())
+}
+
+fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+    let words: Vec<&str> = CODE_WORDS.choose_multiple(&mut rng, 3).copied().collect();
+    format!("{:04}-{}", rng.gen_range(0..10000), words.join("-"))
+}
+
+async fn send(relay_addr: &str, password: &str, cmd: Send, config: Config) -> Result<()> {
+    let code = cmd.code.unwrap_or_else(generate_code);
+    // The text's temporary file has to outlive the transfer
+    let (files, _text_file) = match &cmd.text {
+        Some(text) => {
+            let (files, text_file) = FilesInformation::from_text(text, cmd.hash).await?;
+            (files, Some(text_file))
+        }
+        None => (
+            FilesInformation::from_paths(&cmd.file_name, cmd.hash).await?,
+            None,
+        ),
+    };
+    let files = files.with_compression((!cmd.no_compress).then_some(cmd.compression));
+    info!(
+        "Sending {} items ({} bytes)",
+        files.total_items(),
+        files.total_size()
+    );
+    println!("Code is: {code}");
+    println!("On the other computer run\n\ncroc receive {code}\n");
+    let public = RelayClient::connect(relay_addr, password, &code, cmd.no_local, true).await?;
+    let client = match cmd.local_ports.split_first() {
+        Some((local_port, local_multiplex_ports)) if !cmd.no_local => {
+            // Receivers on the same network can skip the public relay
+            match start_local_relay(password, &code, *local_port, local_multiplex_ports).await {
+                Ok(local) => {
+                    wait_for_receiver_on_either(public.with_local_relay(*local_port), local).await?
+                }
+                Err(err) => {
+                    warn!("Could not start the local relay: {err}");
+                    public.wait_for_receiver().await?
+                }
+            }
         }
+        _ => public.wait_for_receiver().await?,
     };
-    println!("{:?}", input.trim());
-    Ok(())
-}
\ No newline at end of file
+    let (progress, shown) = show_progress();
+    client
+        .with_config(config)
+        .with_progress(progress)
+        .process_client(Some(files))
+        .await?;
+    Ok(shown.await?)
+}
+
+/// Runs a relay for the local network and joins the transfer room on it.
+async fn start_local_relay(
+    password: &str,
+    code: &str,
+    port: u16,
+    multiplex_ports: &[u16],
+) -> Result<RelayClient> {
+    let relay = Relay::new(
+        format!("0.0.0.0:{port}"),
+        password.to_string(),
+        multiplex_ports.to_vec(),
+    );
+    tokio::spawn(relay.start());
+    let address = SocketAddr::from(([127, 0, 0, 1], port));
+    // Give the relay a moment to bind
+    for _ in 0..LOCAL_RELAY_START_ATTEMPTS {
+        if ping(address).await.is_ok() {
+            return RelayClient::connect(address, password, code, true, true).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Err(anyhow!("Local relay is not answering on port {port}"))
+}
+
+async fn receive(relay_addr: &str, password: &str, code: &str, config: Config) -> Result<()> {
+    let client = RelayClient::connect(relay_addr, password, code, false, false)
+        .await?
+        .connect_to_sender()
+        .await?
+        .with_config(config);
+    info!("Receiving through the relay at {}", client.relay().host);
+    let (progress, shown) = show_progress();
+    client.with_progress(progress).process_client(None).await?;
+    Ok(shown.await?)
+}
+
+async fn relay(cmd: RelayOpts, password: String) -> Result<()> {
+    let (main_port, multiplex_ports) = cmd
+        .ports
+        .split_first()
+        .ok_or(anyhow!("At least one relay port is required"))?;
+    let host = cmd.host;
+    let mut relay = Relay::new(
+        format!("{host}:{main_port}"),
+        password,
+        multiplex_ports.to_vec(),
+    )
+    .with_room_ttl(Duration::from_secs(cmd.room_ttl))
+    .with_handshake_timeout(Duration::from_secs(cmd.handshake_timeout))
+    .with_limits(RelayLimits {
+        max_connections: cmd.max_connections,
+        max_rooms: cmd.max_rooms,
+        max_rooms_per_ip: cmd.max_rooms_per_ip,
+        handshakes_per_second: cmd.handshakes_per_second,
+        bytes_per_second: cmd.bytes_per_second,
+        room_bytes_per_second: cmd.room_bytes_per_second,
+        ip_bytes_per_second: cmd.ip_bytes_per_second,
+        max_room_bytes: cmd.max_room_bytes,
+    });
+    if let Some(address) = cmd.metrics {
+        relay = relay.with_metrics(address);
+    }
+    info!("Starting relay on {host}:{main_port}");
+    relay.start().await
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::io::IsTerminal;
+
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle,
+};
+use tokio::{sync::mpsc::UnboundedSender, task::JoinHandle};
+
+use crate::proto::ProgressEvent;
+
+const FILE_TEMPLATE: &str =
+    "{msg:20!} [{bar:30}] {bytes}/{total_bytes} {binary_bytes_per_sec} {eta}";
+const TOTAL_TEMPLATE: &str =
+    "{msg:20!} [{bar:30.cyan}] {bytes}/{total_bytes} {binary_bytes_per_sec} {eta}";
+
+/// Shows the progress of a transfer, as bars on a terminal and as one line
+/// per file otherwise. The returned task ends once the session is dropped.
+pub fn show_progress() -> (UnboundedSender<ProgressEvent>, JoinHandle<()>) {
+    let (progress, mut events) = tokio::sync::mpsc::unbounded_channel();
+    let task = tokio::spawn(async move {
+        let mut output = if std::io::stdout().is_terminal() {
+            Box::new(Bars::default()) as Box<dyn Output + Send>
+        } else {
+            Box::new(Lines::default())
+        };
+        while let Some(event) = events.recv().await {
+            output.show(event);
+        }
+    });
+    (progress, task)
+}
+
+trait Output {
+    fn show(&mut self, event: ProgressEvent);
+}
+
+struct Bars {
+    multi: MultiProgress,
+    total: Option<ProgressBar>,
+    files: HashMap<usize, (ProgressBar, u64)>,
+}
+
+impl Default for Bars {
+    fn default() -> Self {
+        Self {
+            multi: MultiProgress::with_draw_target(ProgressDrawTarget::stdout()),
+            total: None,
+            files: HashMap::new(),
+        }
+    }
+}
+
+impl Bars {
+    fn bar(&self, size: u64, template: &str, message: String) -> ProgressBar {
+        let bar = self.multi.add(ProgressBar::new(size));
+        bar.set_style(
+            ProgressStyle::with_template(template)
+                .unwrap_or_else(|_| ProgressStyle::default_bar())
+                .progress_chars("=> "),
+        );
+        bar.set_message(message);
+        bar
+    }
+    fn finish_file(&mut self, index: usize) {
+        if let Some((bar, _)) = self.files.remove(&index) {
+            bar.finish();
+        }
+    }
+}
+
+impl Output for Bars {
+    fn show(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::TransferStarted { files, bytes } => {
+                self.total = Some(self.bar(bytes, TOTAL_TEMPLATE, format!("{files} items")));
+            }
+            ProgressEvent::FileStarted { index, name, size } => {
+                let bar = self.bar(size, FILE_TEMPLATE, name);
+                self.files.insert(index, (bar, 0));
+            }
+            ProgressEvent::FileSkipped { name, size, .. } => {
+                if let Some(total) = &self.total {
+                    total.set_length(total.length().unwrap_or(0).saturating_sub(size));
+                }
+                let _ = self.multi.println(format!("{name} is already here"));
+            }
+            ProgressEvent::Bytes { index, done, .. } => {
+                if let Some((bar, last)) = self.files.get_mut(&index) {
+                    bar.set_position(done);
+                    if let Some(total) = &self.total {
+                        total.inc(done - *last);
+                    }
+                    *last = done;
+                }
+            }
+            ProgressEvent::FileSent { index, .. } | ProgressEvent::FileVerified { index, .. } => {
+                self.finish_file(index)
+            }
+            ProgressEvent::TextReceived { text } => {
+                let _ = self.multi.println(text);
+            }
+            ProgressEvent::TransferFinished { .. } => {
+                // A resumed transfer never moves the total bar all the way
+                if let Some(total) = self.total.take() {
+                    total.finish();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Lines {
+    // Last throughput seen for each file
+    rates: HashMap<usize, f64>,
+}
+
+impl Output for Lines {
+    fn show(&mut self, event: ProgressEvent) {
+        match event {
+            ProgressEvent::TransferStarted { files, bytes } => {
+                println!("Transferring {files} items ({})", HumanBytes(bytes))
+            }
+            ProgressEvent::FileStarted { name, size, .. } => {
+                println!("{name}: {}", HumanBytes(size))
+            }
+            ProgressEvent::FileSkipped { name, .. } => println!("{name}: already here"),
+            ProgressEvent::Bytes {
+                index,
+                bytes_per_second,
+                ..
+            } => {
+                self.rates.insert(index, bytes_per_second);
+            }
+            ProgressEvent::FileSent { index, name }
+            | ProgressEvent::FileVerified { index, name } => {
+                let rate = self.rates.remove(&index).unwrap_or(0.0);
+                println!("{name}: done ({}/s)", HumanBytes(rate as u64))
+            }
+            ProgressEvent::TextReceived { text } => println!("{text}"),
+            ProgressEvent::TransferFinished { bytes, elapsed } => println!(
+                "Transferred {} in {}",
+                HumanBytes(bytes),
+                HumanDuration(elapsed)
+            ),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::{
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+// zstd level trading ratio for speed, close to what flate's fast level does
+const ZSTD_LEVEL: i32 = 3;
+
+/// How file chunks are compressed, named the way they are sent in
+/// `FilesInformation::compression`. Go croc only knows flate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionAlgorithm {
+    #[default]
+    Flate,
+    Zstd,
+}
+
+impl CompressionAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Flate => "flate",
+            CompressionAlgorithm::Zstd => "zstd",
+        }
+    }
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            CompressionAlgorithm::Flate => compress(data),
+            CompressionAlgorithm::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+        }
+    }
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            CompressionAlgorithm::Flate => decompress(data),
+            CompressionAlgorithm::Zstd => Ok(zstd::stream::decode_all(data)?),
+        }
+    }
+}
+
+impl fmt::Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for CompressionAlgorithm {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "flate" => Ok(CompressionAlgorithm::Flate),
+            "zstd" => Ok(CompressionAlgorithm::Zstd),
+            _ => Err(anyhow!("Unsupported compression {s}")),
+        }
+    }
+}
+
+/// Compresses `data` as a raw deflate stream, like Go's `compress/flate`
+/// which croc uses for its messages.
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::fast());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decompressed = vec![];
+    DeflateDecoder::new(data).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CompressionAlgorithm;
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"croc croc croc croc croc croc croc croc".repeat(100);
+        for compression in [CompressionAlgorithm::Flate, CompressionAlgorithm::Zstd] {
+            let compressed = compression.compress(&data).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(compression.decompress(&compressed).unwrap(), data);
+        }
+    }
+}
@@ -1,9 +1,117 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Result};
+
+use super::interaction::{Interaction, TerminalInteraction};
+
 pub struct Config {
-    ask: bool
+    policy: ReceivePolicy,
+    multiplex: bool,
+    interaction: Arc<dyn Interaction>,
+    base_dir: PathBuf,
+}
+
+impl Config {
+    pub fn with_multiplex(mut self, multiplex: bool) -> Self {
+        self.multiplex = multiplex;
+        self
+    }
+    pub fn with_policy(mut self, policy: ReceivePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+    /// Who answers the questions the policy leaves open.
+    pub fn with_interaction(mut self, interaction: impl Interaction + 'static) -> Self {
+        self.interaction = Arc::new(interaction);
+        self
+    }
+    /// The folder received files are put in, nothing is written outside of it.
+    pub fn with_base_dir(mut self, base_dir: impl Into<PathBuf>) -> Self {
+        self.base_dir = base_dir.into();
+        self
+    }
+    pub fn policy(&self) -> &ReceivePolicy {
+        &self.policy
+    }
+    /// Whether chunks are spread over every relay port or sent on the first one.
+    pub fn multiplex(&self) -> bool {
+        self.multiplex
+    }
+    pub fn interaction(&self) -> &Arc<dyn Interaction> {
+        &self.interaction
+    }
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Self { ask: true }
+        Self {
+            policy: ReceivePolicy::default(),
+            multiplex: true,
+            interaction: Arc::new(TerminalInteraction),
+            // The current directory, wherever it is when the files arrive
+            base_dir: PathBuf::from("."),
+        }
     }
-}
\ No newline at end of file
+}
+
+/// What the receiver does with incoming files without asking anyone.
+#[derive(Debug, Clone, Default)]
+pub struct ReceivePolicy {
+    /// Accept the offered files without a prompt.
+    pub auto_accept: bool,
+    /// What to do when a file is already there.
+    pub overwrite: OverwriteMode,
+    /// Refuse transfers bigger than this many bytes.
+    pub max_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwriteMode {
+    /// Ask the interaction for every file that exists.
+    #[default]
+    Prompt,
+    /// Keep the existing file and don't receive the new one.
+    Skip,
+    /// Replace the existing file, resuming it when it looks like a partial transfer.
+    Overwrite,
+    /// Receive the file next to the existing one under a free name.
+    Rename,
+}
+
+impl OverwriteMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OverwriteMode::Prompt => "prompt",
+            OverwriteMode::Skip => "skip",
+            OverwriteMode::Overwrite => "overwrite",
+            OverwriteMode::Rename => "rename",
+        }
+    }
+}
+
+impl fmt::Display for OverwriteMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for OverwriteMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "prompt" => Ok(OverwriteMode::Prompt),
+            "skip" => Ok(OverwriteMode::Skip),
+            "overwrite" => Ok(OverwriteMode::Overwrite),
+            "rename" => Ok(OverwriteMode::Rename),
+            _ => Err(anyhow!("Unsupported overwrite mode {s}")),
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use inquire::Confirm;
+
+use crate::proto::FilesInformation;
+
+/// The questions a receiving session may need answered. Implementations may
+/// block, they are always called off the async runtime.
+pub trait Interaction: Send + Sync {
+    /// Whether the offered files should be received at all.
+    fn accept_transfer(&self, files: &FilesInformation) -> Result<bool>;
+    /// Whether `path`, which already exists, may be written over.
+    fn confirm_overwrite(&self, path: &Path) -> Result<bool>;
+}
+
+/// Asks on the terminal. Fails instead of blocking when there is no terminal
+/// to ask on.
+pub struct TerminalInteraction;
+
+impl Interaction for TerminalInteraction {
+    fn accept_transfer(&self, files: &FilesInformation) -> Result<bool> {
+        confirm(&format!(
+            "Should receive {} items ({} bytes)",
+            files.total_items(),
+            files.total_size()
+        ))
+    }
+    fn confirm_overwrite(&self, path: &Path) -> Result<bool> {
+        confirm(&format!("{path:?} exists, do you want to overwrite?"))
+    }
+}
+
+fn confirm(message: &str) -> Result<bool> {
+    Confirm::new(message)
+        .prompt()
+        .context(format!("Could not ask \"{message}\""))
+}
+
+/// Says yes to everything.
+pub struct AutoAccept;
+
+impl Interaction for AutoAccept {
+    fn accept_transfer(&self, _files: &FilesInformation) -> Result<bool> {
+        Ok(true)
+    }
+    fn confirm_overwrite(&self, _path: &Path) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Runs `question` against `interaction` on the blocking thread pool.
+pub(crate) async fn ask<F>(interaction: &Arc<dyn Interaction>, question: F) -> Result<bool>
+where
+    F: FnOnce(&dyn Interaction) -> Result<bool> + Send + 'static,
+{
+    let interaction = interaction.clone();
+    tokio::task::spawn_blocking(move || question(interaction.as_ref())).await?
+}
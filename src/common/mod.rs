@@ -1 +1,4 @@
+pub mod compress;
 pub mod config;
+pub mod interaction;
+pub mod pio;
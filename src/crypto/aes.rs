@@ -55,7 +55,7 @@ impl AesEncryptor {
 
         // Derive a strong key using PBKDF2-HMAC-SHA256
         let strong_key = pbkdf2_hmac_array::<Sha256, 32>(session_key, &salt, 100);
-        debug!("Derived strong_key: {:x?}", hex::encode(&strong_key));
+        debug!("Derived strong_key: {:x?}", hex::encode(strong_key));
 
         // Create an AES-GCM cipher instance with the strong key
         let cipher = aes_gcm::Aes256Gcm::new((&strong_key).into());
@@ -134,6 +134,9 @@ impl AesEncryptor {
     /// assert_eq!(data.to_vec(), decrypted_data);
     /// ```
     pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < 12 {
+            return Err(Error::msg("Encrypted data is too short to hold a nonce"));
+        }
         // Extract the nonce from the encrypted data (first 12 bytes)
         let nonce = &data[..12];
 
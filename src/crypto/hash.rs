@@ -0,0 +1,201 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh64::Xxh64;
+
+// imohash only samples files bigger than this
+const IMOHASH_SAMPLE_THRESHOLD: u64 = 128 * 1024;
+const IMOHASH_SAMPLE_SIZE: u64 = 16 * 1024;
+const READ_BUFFER_SIZE: usize = 1024 * 64;
+
+/// The file digests croc knows about, named the way they are sent in
+/// `FilesInformation::hash_algorithm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Xxhash,
+    Imohash,
+}
+
+impl HashAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Xxhash => "xxhash",
+            HashAlgorithm::Imohash => "imohash",
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            "xxhash" => Ok(HashAlgorithm::Xxhash),
+            "imohash" => Ok(HashAlgorithm::Imohash),
+            _ => Err(anyhow!("Unsupported hash algorithm {s}")),
+        }
+    }
+}
+
+/// Hashes the file at `path` on the blocking thread pool.
+///
+/// The digests are laid out like the Go croc ones: xxhash is the big endian
+/// XXH64 and imohash is a sampled murmur3 with the file size varint encoded
+/// at its start.
+pub async fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+    let path: PathBuf = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        hash_reader(&mut BufReader::new(file), size, algorithm)
+    })
+    .await?
+}
+
+/// Hashes `data` like `hash_file` hashes a file holding it.
+pub fn hash_bytes(data: &[u8], algorithm: HashAlgorithm) -> Result<Vec<u8>> {
+    hash_reader(&mut Cursor::new(data), data.len() as u64, algorithm)
+}
+
+fn hash_reader<R: Read + Seek>(
+    reader: &mut R,
+    size: u64,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<u8>> {
+    match algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            for_each_block(reader, |block| hasher.update(block))?;
+            Ok(hasher.finalize().to_vec())
+        }
+        HashAlgorithm::Xxhash => {
+            let mut hasher = Xxh64::new(0);
+            for_each_block(reader, |block| hasher.update(block))?;
+            Ok(hasher.digest().to_be_bytes().to_vec())
+        }
+        HashAlgorithm::Imohash => imohash(reader, size),
+    }
+}
+
+fn for_each_block<R: Read>(reader: &mut R, mut f: impl FnMut(&[u8])) -> Result<()> {
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        let amount = reader.read(&mut buffer)?;
+        if amount == 0 {
+            return Ok(());
+        }
+        f(&buffer[..amount]);
+    }
+}
+
+fn imohash<R: Read + Seek>(reader: &mut R, size: u64) -> Result<Vec<u8>> {
+    let hash = if size < IMOHASH_SAMPLE_THRESHOLD {
+        murmur3::murmur3_x64_128(reader, 0)?
+    } else {
+        let mut samples = vec![0u8; 3 * IMOHASH_SAMPLE_SIZE as usize];
+        let offsets = [0, size / 2, size - IMOHASH_SAMPLE_SIZE];
+        for (sample, offset) in samples
+            .chunks_mut(IMOHASH_SAMPLE_SIZE as usize)
+            .zip(offsets)
+        {
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(sample)?;
+        }
+        murmur3::murmur3_x64_128(&mut samples.as_slice(), 0)?
+    };
+    // murmur3 packs h1 in the low half, Go writes h1 then h2 big endian
+    let mut digest = ((hash as u64).to_be_bytes()).to_vec();
+    digest.extend_from_slice(&((hash >> 64) as u64).to_be_bytes());
+    // Prefix with the uvarint encoded size
+    let mut remaining = size;
+    let mut position = 0;
+    while remaining >= 0x80 {
+        digest[position] = (remaining as u8) | 0x80;
+        remaining >>= 7;
+        position += 1;
+    }
+    digest[position] = remaining as u8;
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
+    use super::{
+        hash_bytes, hash_file, HashAlgorithm, IMOHASH_SAMPLE_SIZE, IMOHASH_SAMPLE_THRESHOLD,
+    };
+
+    fn file_with(content: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn test_known_digests() {
+        let file = file_with(b"abc");
+        assert_eq!(
+            hex::encode(hash_file(file.path(), HashAlgorithm::Sha256).await.unwrap()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex::encode(hash_file(file.path(), HashAlgorithm::Xxhash).await.unwrap()),
+            "44bc2cf5ad770999"
+        );
+        for algorithm in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Xxhash,
+            HashAlgorithm::Imohash,
+        ] {
+            assert_eq!(
+                hash_bytes(b"abc", algorithm).unwrap(),
+                hash_file(file.path(), algorithm).await.unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_imohash_samples_big_files() {
+        let size = (IMOHASH_SAMPLE_THRESHOLD * 4) as usize;
+        let original = vec![7u8; size];
+        let hash = hash_file(file_with(&original).path(), HashAlgorithm::Imohash)
+            .await
+            .unwrap();
+        // uvarint of 512KiB
+        assert_eq!(&hash[..3], &[0x80, 0x80, 0x20]);
+
+        // Bytes outside the samples are not part of the hash
+        let mut unsampled = original.clone();
+        unsampled[IMOHASH_SAMPLE_SIZE as usize + 1] = 8;
+        let unsampled_hash = hash_file(file_with(&unsampled).path(), HashAlgorithm::Imohash)
+            .await
+            .unwrap();
+        assert_eq!(hash, unsampled_hash);
+
+        let mut sampled = original;
+        sampled[size / 2] = 8;
+        let sampled_hash = hash_file(file_with(&sampled).path(), HashAlgorithm::Imohash)
+            .await
+            .unwrap();
+        assert_ne!(hash, sampled_hash);
+    }
+}
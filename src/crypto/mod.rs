@@ -1,2 +1,3 @@
 #[macro_use]
-pub mod aes;
\ No newline at end of file
+pub mod aes;
+pub mod hash;
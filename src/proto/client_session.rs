@@ -1,54 +1,111 @@
-use std::{convert::TryInto, sync::Arc};
+use std::{
+    convert::{TryFrom, TryInto},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::crypto::aes::AesEncryptor;
-use anyhow::{anyhow, Result};
-use inquire::Confirm;
+use crate::crypto::{aes::AesEncryptor, hash::HashAlgorithm};
+use anyhow::{anyhow, Context, Result};
 use rand::RngCore;
 use rust_pake::pake::{Pake, Role};
-use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use tokio::{fs::File, sync::Mutex};
+use tokio::{fs::File, sync::mpsc::UnboundedSender};
 
 use crate::{
-    common::config::Config,
-    proto::{AsyncCrocRead, AsyncCrocWrite},
+    common::{
+        config::{Config, OverwriteMode},
+        interaction::ask,
+    },
+    proto::AsyncCrocRead,
     relay::{
-        client::RelayClient,
-        fs::{CrocFsInterface, FileChunk, FileChunkInfo},
+        client::{room_name, split_secret, RelayClient, RelayInfo},
+        fs::{
+            chunk_ranges_to_offsets, decode_chunk, missing_chunks, CrocFsInterface, FileChunk,
+            FileChunkInfo,
+        },
     },
 };
 
 use super::{
     croc_msg::{
-        ExternalIPMessage, FilesInformation, Message, PakeMessage, RemoteFileRequest,
-        TypeErrorMessage,
+        is_hash_mismatch, ExternalIPMessage, FileInfo, FilesInformation, Message, PakeMessage,
+        RemoteFileRequest, TypeErrorMessage,
     },
-    croc_raw::{MpscCrocProto, ProtoError},
+    croc_raw::{MpscCrocProto, OwnedReceiver, ProtoError},
+    progress::{FileProgress, ProgressEvent},
+    session_state::ClientState,
     CrocProto, EncryptedSession, OwnedSender,
 };
 const TCP_BUFFER_SIZE: i32 = 1024 * 64;
-#[derive(Serialize, Deserialize)]
-struct FileInfo {
-    #[serde(rename = "Name")]
-    name: String,
+// How long the sender waits for the receiver to hang up once done, when the
+// relay runs in this process
+const HANGUP_TIMEOUT: Duration = Duration::from_secs(1);
+// Go croc reads and requests files in chunks of half its buffer
+const CHUNK_SIZE: u64 = TCP_BUFFER_SIZE as u64 / 2;
+// What Go croc's receiver tells the sender when it does not want the files
+const REFUSAL: &str = "refusing files";
+// Text is received in memory, anything bigger is refused
+const MAX_TEXT_SIZE: usize = 1024 * 1024;
+
+/// Why a session ended before the transfer was done.
+#[derive(thiserror::Error, Debug)]
+pub enum SessionError {
+    /// The files were not accepted on this side.
+    #[error("Transfer refused")]
+    Refused,
+    /// The peer did not accept the files.
+    #[error("The peer refused the transfer")]
+    PeerRefused,
+    /// The peer stopped because of an error on its side.
+    #[error("The peer failed: {0}")]
+    Remote(String),
+    /// A message that makes no sense at this point of the session.
+    #[error("Unexpected {message} message while in {state}")]
+    Protocol {
+        message: &'static str,
+        state: String,
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
 }
-#[derive(Default, Debug, PartialEq)]
-enum ClientState {
-    #[default]
-    KeyExchange,
-    IpExchange,
-    FileInfoTransfare,
-    FileTransfare,
-    FileTransfared,
+
+impl SessionError {
+    fn from_peer(message: String) -> Self {
+        if message == REFUSAL {
+            SessionError::PeerRefused
+        } else {
+            SessionError::Remote(message)
+        }
+    }
+    /// What to tell the peer about this error, nothing when it came from the peer.
+    fn peer_message(&self) -> Option<String> {
+        match self {
+            SessionError::Refused => Some(REFUSAL.to_string()),
+            SessionError::PeerRefused | SessionError::Remote(_) => None,
+            SessionError::Other(err) => Some(format!("{err:#}")),
+            err => Some(err.to_string()),
+        }
+    }
 }
-trait Process<T> {
-    async fn process(self, msg: Message) -> Result<T>;
+
+impl From<anyhow::Error> for SessionError {
+    fn from(err: anyhow::Error) -> Self {
+        err.downcast::<SessionError>()
+            .or_else(|err| err.downcast::<std::io::Error>().map(SessionError::Io))
+            .unwrap_or_else(SessionError::Other)
+    }
 }
 
 pub struct ClientSession {
     state: ClientState,
     pub stream: CrocProto,
-    relay_ports: Vec<String>,
+    relay: RelayInfo,
+    // The relay is the one the sender embeds
+    on_local_relay: bool,
     encrypted_session: Option<EncryptedSession>,
     shared_secret: String,
     pub is_sender: bool,
@@ -56,44 +113,43 @@ pub struct ClientSession {
     peer_external_ip: Option<String>,
     key: Option<Pake<rust_pake::pake::SIEC255Params>>,
 
-    // The whole design here is broken... This struct should be generic
-    // in its impl for Receiver and Sender. That way we can maintain one files field that can
+    // What the sender offered, only set on the receiving side
     files_to_receive: Option<FilesInformation>,
     config: Config,
+    progress: Option<UnboundedSender<ProgressEvent>>,
+    // Bytes sent or received over the whole session
+    transferred: u64,
 }
 
 // receiver_task will receive a message from the client relay and write it to the sender_ipc channel
-async fn start_net_task(relay_port: String, shared_secret: String) -> Result<MpscCrocProto> {
-    // Connect to relay using relay client
-    let default_relay_addr = "localhost";
-
-    // join host and port
-    let relay_address = format!("{}:{}", default_relay_addr, relay_port);
+async fn start_net_task(
+    relay: &RelayInfo,
+    relay_port: &str,
+    shared_secret: &str,
+    index: usize,
+) -> Result<MpscCrocProto> {
+    // The data connections go to the relay the session was started on
+    let relay_address = relay.address(relay_port)?;
+    // Data rooms are named like in Go croc: <first 6 hex chars of sha256(room)>-<index>
     let mut hasher = Sha256::new();
-    hasher.update(&shared_secret.as_bytes()[5..]);
-    // generate hex string from hash
-    let shared_secret = &format!("{:x}", hasher.finalize())[..6];
+    hasher.update(room_name(shared_secret)?.as_bytes());
+    let room = format!("{}-{}", &format!("{:x}", hasher.finalize())[..6], index);
     debug!("Connecting to relay at {}", relay_address);
-    RelayClient::connect(
-        &relay_address,
-        &"pass123".to_string(),
-        &format!("{}-1", shared_secret),
-        false,
-    )
-    .await?
-    .start_mpsc_stream()
+    RelayClient::connect_to_room(relay_address, &relay.password, &room)
+        .await?
+        .start_mpsc_stream()
 }
 async fn start_fs_task(
-    sender_tx: OwnedSender,
+    senders_tx: Vec<OwnedSender>,
     encrypted_session: EncryptedSession,
 ) -> Result<CrocFsInterface> {
-    CrocFsInterface::new(sender_tx, encrypted_session).await
+    CrocFsInterface::new(senders_tx, encrypted_session).await
 }
 
 impl ClientSession {
     pub fn new(
         stream: CrocProto,
-        relay_ports: Vec<String>,
+        relay: RelayInfo,
         shared_secret: String,
         // this is redundent and bad
         is_sender: bool,
@@ -103,7 +159,8 @@ impl ClientSession {
         Self {
             state: ClientState::KeyExchange,
             stream,
-            relay_ports,
+            relay,
+            on_local_relay: false,
             encrypted_session: None,
             shared_secret,
             is_sender,
@@ -112,144 +169,536 @@ impl ClientSession {
             key: None,
             files_to_receive: None,
             config: config.unwrap_or_default(),
+            progress: None,
+            transferred: 0,
+        }
+    }
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+    /// Reports the transfer's progress on `progress`.
+    pub fn with_progress(mut self, progress: UnboundedSender<ProgressEvent>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+    /// Marks the session as going through the relay this process runs.
+    pub fn on_local_relay(mut self) -> Self {
+        self.on_local_relay = true;
+        self
+    }
+    /// The relay the data connections go through.
+    pub fn relay(&self) -> &RelayInfo {
+        &self.relay
+    }
+
+    /// Runs the session to its end. On failure the peer is told why, so it
+    /// stops too.
+    pub async fn process_client(
+        mut self,
+        files: Option<FilesInformation>,
+    ) -> Result<(), SessionError> {
+        let err = match self.run(files).await {
+            Ok(()) => return Ok(()),
+            Err(err) => SessionError::from(err),
+        };
+        if let Some(message) = err.peer_message() {
+            // The connection may well be what failed
+            let _ = Message::TypeError(TypeErrorMessage { message })
+                .send(&mut self.stream, self.encrypted_session.as_ref())
+                .await;
         }
+        Err(err)
     }
 
     // TODO: this should be split to send and recv
-    pub async fn process_client(mut self, files: Option<FilesInformation>) -> Result<()> {
+    async fn run(&mut self, files: Option<FilesInformation>) -> Result<()> {
         debug!("Starting Client Processing");
-        let _port = self
-            .relay_ports
-            .first()
-            .ok_or(anyhow!("Error, no relay port given"))?
-            .clone();
-        let secret = self.shared_secret.clone();
-        let net = start_net_task(self.relay_ports[0].clone(), secret).await?;
-        let (mut receiver, sender) = net.into_split();
+        let started = Instant::now();
+        if self.relay.ports.is_empty() {
+            return Err(anyhow!("Error, no relay port given"));
+        }
+        // The receiver joins the data rooms once the files info tells it how
+        // many of them the sender uses
+        let senders = if self.is_sender {
+            self.join_data_rooms(self.config.multiplex()).await?.1
+        } else {
+            vec![]
+        };
         let mut rw = None;
 
         if !self.is_sender {
             debug!("Receiver Started: Sending initial key");
             self.key = Some(Pake::new(
                 Role::Sender,
-                self.shared_secret[5..].as_bytes().into(),
+                split_secret(&self.shared_secret)?.1.as_bytes().into(),
             ));
             PakeMessage::new(&self.key.as_ref().unwrap().pub_pake, "siec".to_string())?
-                .send(&mut self.stream)
+                .send(&mut self.stream, self.encrypted_session.as_ref())
                 .await?;
         } else {
             debug!("Sender Started: Should get key req");
         }
         loop {
-            debug!("Waiting for message");
-            self.step()?;
-            let msg = Message::recv(&mut self.stream).await?;
+            debug!(
+                "{}: waiting for a message in {:?}",
+                if self.is_sender { "Sender" } else { "Receiver" },
+                self.state
+            );
+            let msg = Message::recv(&mut self.stream, self.encrypted_session.as_ref()).await?;
             debug!("Got Message");
-            self.step()?;
+            let next = self
+                .state
+                .next(self.is_sender, msg.kind())
+                .ok_or_else(|| self.unexpected(&msg))?;
+            self.state = next;
             match msg {
                 Message::Pake(msg) => {
                     self.process_key_exchange(msg).await?;
                     let tmp_fs = start_fs_task(
-                        sender.clone(),
+                        senders.clone(),
                         self.encrypted_session.as_ref().unwrap().clone(),
                     )
                     .await?;
                     rw = Some(tmp_fs.into_split());
                 }
-                Message::ExternalIP(msg) => self.process_ip_exchange(msg).await?,
+                Message::ExternalIP(msg) => {
+                    self.process_ip_exchange(msg).await?;
+                    if self.is_sender {
+                        let files = files.as_ref().context("Nothing to send")?;
+                        debug!("Sending files info");
+                        self.emit(ProgressEvent::TransferStarted {
+                            files: files.total_items(),
+                            bytes: files.total_size() as u64,
+                        });
+                        let mut files = files.clone();
+                        files.no_multiplexing = !self.config.multiplex();
+                        Message::FilesInfo(files)
+                            .send(&mut self.stream, self.encrypted_session.as_ref())
+                            .await?;
+                    }
+                }
+                Message::FilesInfo(files) => {
+                    let multiplex = !files.no_multiplexing;
+                    self.process_files_info(files).await?;
+                    let (mut receiver, _) = self.join_data_rooms(multiplex).await?;
+                    let (_, writer) = rw.as_ref().context("No file writer after key exchange")?;
+                    self.receive_files(&mut receiver, writer).await?;
+                    Message::Finished
+                        .send(&mut self.stream, self.encrypted_session.as_ref())
+                        .await?;
+                }
+                Message::TypeRecipientReady(msg) => {
+                    let (reader, _) = rw.as_ref().context("No file reader after key exchange")?;
+                    let files = files.as_ref().context("Nothing to send")?;
+                    self.send_file(reader, msg, files).await?
+                }
                 Message::Finished => {
                     // send finished
-                    Message::Finished.send(&mut self.stream).await?;
+                    Message::Finished
+                        .send(&mut self.stream, self.encrypted_session.as_ref())
+                        .await?;
+                    self.emit(ProgressEvent::TransferFinished {
+                        bytes: self.transferred,
+                        elapsed: started.elapsed(),
+                    });
+                    if self.is_sender && self.on_local_relay {
+                        // Let the receiver get the reply before the relay goes away
+                        let _ = tokio::time::timeout(HANGUP_TIMEOUT, self.stream.read()).await;
+                    }
                     return Ok(());
                 }
-                Message::FilesInfo(files) => self.process_files_info(files).await?,
-                // Assume files is not none
-                Message::TypeRecipientReady(msg) => match &rw {
-                    Some((reader, _)) => {
-                        self.send_file(&reader, msg, files.as_ref().unwrap())
-                            .await?
-                    }
-                    None => todo!(),
-                },
-                Message::TypeError(_) => todo!(),
+                Message::CloseSender => {
+                    Message::CloseRecipient
+                        .send(&mut self.stream, self.encrypted_session.as_ref())
+                        .await?;
+                }
+                // Only ever read as the answer to our CloseSender
+                Message::CloseRecipient => return Err(self.unexpected(&msg).into()),
+                Message::TypeError(msg) => return Err(SessionError::from_peer(msg.message).into()),
             }
-            if self.is_sender && self.state == ClientState::FileInfoTransfare {
-                debug!("Sending files info");
-                // Again the whole concept of the optional here is just bad.
-                Message::FilesInfo(files.clone().unwrap())
-                    .send(&mut self.stream)
-                    .await?;
-                self.state = ClientState::FileTransfare;
+        }
+    }
+    /// Joins one data room per relay port, or only the first one without
+    /// `multiplex`.
+    async fn join_data_rooms(&self, multiplex: bool) -> Result<(OwnedReceiver, Vec<OwnedSender>)> {
+        let ports = if multiplex {
+            &self.relay.ports[..]
+        } else {
+            &self.relay.ports[..1]
+        };
+        let mut receivers = vec![];
+        let mut senders = vec![];
+        for (index, port) in ports.iter().enumerate() {
+            let (receiver, sender) = start_net_task(&self.relay, port, &self.shared_secret, index)
+                .await?
+                .into_split();
+            receivers.push(receiver);
+            senders.push(sender);
+        }
+        Ok((OwnedReceiver::merge(receivers), senders))
+    }
+    /// Requests and receives every accepted file, one after the other.
+    async fn receive_files(
+        &mut self,
+        receiver: &mut OwnedReceiver,
+        writer: &tokio::sync::mpsc::Sender<FileChunk>,
+    ) -> Result<()> {
+        // Cloned, the session is borrowed mutably while receiving
+        let Some(files_info) = self.files_to_receive.clone() else {
+            return Ok(());
+        };
+        info!("Starting to receive files");
+        let hash_algorithm = HashAlgorithm::from_str(&files_info.hash_algorithm)
+            .map_err(|err| warn!("{err}, received files will not be verified"))
+            .ok();
+        if files_info.sending_text {
+            // Checked to be a single file when the files were offered
+            let Some([file_info]) = files_info.files_to_transfare.as_deref() else {
+                return Err(anyhow!("Text must be a single file"));
+            };
+            let text = self
+                .receive_text(receiver, file_info, hash_algorithm)
+                .await?;
+            self.emit(ProgressEvent::TextReceived { text });
+            return Ok(());
+        }
+        // Owned, the session is borrowed mutably while receiving
+        let base = &self.base_dir().to_path_buf();
+        // Created last so no received file can be written through them
+        let mut symlinks = vec![];
+        // loop all files and request them one by one
+        for (index, file_info) in files_info.files_to_transfare.iter().flatten().enumerate() {
+            if let Some(hash_algorithm) = hash_algorithm {
+                if file_info.already_received(base, hash_algorithm).await? {
+                    info!(
+                        "Skipping {:?}, it was already received",
+                        file_info.remote_path(base)?
+                    );
+                    self.emit(ProgressEvent::FileSkipped {
+                        index,
+                        name: file_info.name.clone(),
+                        size: file_info.size as u64,
+                    });
+                    continue;
+                }
             }
-            if !self.is_sender && self.state == ClientState::FileTransfare {
-                info!("Starting to receive files");
-                // loop all files and request them one by one
-                for (index, file_info) in self
-                    .files_to_receive
-                    .as_ref()
-                    .unwrap()
-                    .files_to_transfare
-                    .as_ref()
-                    .unwrap()
-                    .iter()
-                    .enumerate()
-                {
-                    let remote_path = std::path::Path::new(&file_info.remote_folder)
-                        .join(&file_info.name)
-                        .to_str()
-                        .unwrap()
-                        .to_string();
-                    debug!("Requesting file: {}", remote_path);
-                    // create the file
-                    let file = tokio::fs::File::create(remote_path).await?;
-
+            let Some(file_info) = self.resolve_existing(file_info).await? else {
+                info!(
+                    "Skipping {:?}, it already exists",
+                    file_info.remote_path(base)?
+                );
+                self.emit(ProgressEvent::FileSkipped {
+                    index,
+                    name: file_info.name.clone(),
+                    size: file_info.size as u64,
+                });
+                continue;
+            };
+            if !file_info.symlink.is_empty() {
+                symlinks.push(file_info);
+                continue;
+            }
+            let remote_path = file_info.remote_path(base)?;
+            debug!("Requesting file: {:?}", remote_path);
+            // create the file, and the folders leading to it
+            tokio::fs::create_dir_all(file_info.remote_folder_path(base)?).await?;
+            // A file of the right size is a previous interrupted transfer, only
+            // ask for the chunks it is missing. Links are never followed, the
+            // file replaces them
+            let existing = tokio::fs::symlink_metadata(&remote_path).await;
+            if existing
+                .as_ref()
+                .is_ok_and(|metadata| metadata.file_type().is_symlink())
+            {
+                tokio::fs::remove_file(&remote_path).await?;
+            }
+            let (file, current_file_chunk_ranges) = match existing {
+                Ok(metadata) if metadata.is_file() && metadata.len() == file_info.size as u64 => {
+                    let ranges = missing_chunks(&remote_path, CHUNK_SIZE as usize).await?;
+                    debug!("Resuming {:?} with chunk ranges {:?}", remote_path, ranges);
+                    let file = tokio::fs::OpenOptions::new()
+                        .write(true)
+                        .open(&remote_path)
+                        .await?;
+                    (file, ranges)
+                }
+                _ => {
+                    let file = tokio::fs::File::create(&remote_path).await?;
                     file.set_len(file_info.size as u64).await?;
-                    // request the file
-                    Message::TypeRecipientReady(RemoteFileRequest {
-                        files_to_transfer_current_num: index as i64,
-                        machine_id: "".to_string(),
-                        current_file_chunk_ranges: vec![],
-                    })
-                    .send(&mut self.stream)
-                    .await?;
+                    (file, vec![])
+                }
+            };
+            let file = Arc::new(file.into_std().await);
+            let resumed = !current_file_chunk_ranges.is_empty();
+            self.receive_file(
+                receiver,
+                writer,
+                index,
+                &file_info,
+                &file,
+                current_file_chunk_ranges,
+            )
+            .await?;
+            if let Some(hash_algorithm) = hash_algorithm.filter(|_| !file_info.hash.is_empty()) {
+                match file_info.verify(base, hash_algorithm).await {
+                    // What was there may have been an older version of the file and not
+                    // an interrupted transfer, it is received whole once more
+                    Err(err) if resumed && is_hash_mismatch(&err) => {
+                        warn!(
+                            "Resumed {:?} does not match, receiving it again",
+                            file_info.name
+                        );
+                        let (cleared, size) = (file.clone(), file_info.size as u64);
+                        tokio::task::spawn_blocking(move || {
+                            cleared.set_len(0)?;
+                            cleared.set_len(size)
+                        })
+                        .await??;
+                        self.receive_file(receiver, writer, index, &file_info, &file, vec![])
+                            .await?;
+                        file_info.verify(base, hash_algorithm).await?
+                    }
+                    result => result?,
+                }
+            }
+            file_info.apply_metadata(base).await?;
+            self.emit(ProgressEvent::FileVerified {
+                index,
+                name: file_info.name.clone(),
+            });
+        }
+        for symlink in symlinks {
+            symlink.create_symlink(base).await?;
+        }
+        Ok(())
+    }
+    /// Requests `current_file_chunk_ranges` of file `index` and writes them to
+    /// `file`, then closes the file with the sender.
+    async fn receive_file(
+        &mut self,
+        receiver: &mut OwnedReceiver,
+        writer: &tokio::sync::mpsc::Sender<FileChunk>,
+        index: usize,
+        file_info: &FileInfo,
+        file: &Arc<std::fs::File>,
+        current_file_chunk_ranges: Vec<i64>,
+    ) -> Result<()> {
+        let compression = self
+            .files_to_receive
+            .as_ref()
+            .context("No files to receive")?
+            .chunk_compression()?;
+        // Count bytes and not chunks, the sender may split the file differently
+        let total_bytes: u64 = chunk_ranges_to_offsets(
+            &current_file_chunk_ranges,
+            file_info.size as u64,
+            CHUNK_SIZE,
+        )?
+        .iter()
+        .map(|offset| CHUNK_SIZE.min(file_info.size as u64 - offset))
+        .sum();
+        // request the file
+        Message::TypeRecipientReady(RemoteFileRequest {
+            files_to_transfer_current_num: index as i64,
+            machine_id: "".to_string(),
+            current_file_chunk_ranges,
+        })
+        .send(&mut self.stream, self.encrypted_session.as_ref())
+        .await?;
+        self.emit(ProgressEvent::FileStarted {
+            index,
+            name: file_info.name.clone(),
+            size: total_bytes,
+        });
 
-                    let total_chunks = file_info.size.div_ceil(TCP_BUFFER_SIZE as i64);
-                    let mut current_amount = 0;
-                    let file = Arc::new(Mutex::new(file));
-                    // receive the file
-                    while current_amount < total_chunks
-                        && let Ok(chunk) = receiver.read().await
-                    {
-                        let mv_file = file.clone();
-                        debug!("Current: {current_amount}/{total_chunks}");
-                        match &rw {
-                            Some((_, writer)) => {
-                                writer
-                                    .send(FileChunk {
-                                        file: mv_file,
-                                        data: chunk,
-                                    })
-                                    .await?;
-                                current_amount += 1;
-                            }
-                            None => panic!("Should not happen"),
-                        }
+        let mut file_progress = FileProgress::new(index, total_bytes);
+        let mut current_amount = 0;
+        // The writer reports how many bytes each chunk held once it is written
+        let (written, mut written_receiver) = tokio::sync::mpsc::channel(100);
+        // A chunk read from the relay that the writer has no room for yet
+        let mut pending = None;
+        let mut peeked = [0u8; 1];
+        // receive the file
+        while current_amount < total_bytes {
+            tokio::select! {
+                chunk = receiver.read(), if pending.is_none() => {
+                    let chunk = chunk?;
+                    if chunk == [1u8] {
+                        // The relay pings whoever joined the data room first
+                        debug!("Got ping");
+                        continue;
+                    }
+                    pending = Some(chunk);
+                }
+                // Waiting here instead of in a send keeps the written chunks
+                // counted, the writer waits on them when it is full
+                permit = writer.reserve(), if pending.is_some() => {
+                    permit?.send(FileChunk {
+                        file: file.clone(),
+                        data: pending.take().unwrap(),
+                        compression,
+                        written: written.clone(),
+                    });
+                }
+                Some(amount) = written_receiver.recv() => {
+                    let amount = amount? as u64;
+                    current_amount += amount;
+                    self.transferred += amount;
+                    self.emit(file_progress.advance(amount));
+                    debug!("Current: {current_amount}/{total_bytes}");
+                }
+                // The sender says nothing during a file unless it failed, peeking
+                // leaves the message whole when another branch wins
+                amount = self.stream.peek(&mut peeked) => {
+                    if amount? == 0 {
+                        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                    }
+                    let msg = Message::recv(&mut self.stream, self.encrypted_session.as_ref()).await?;
+                    return Err(match msg {
+                        Message::TypeError(msg) => SessionError::from_peer(msg.message),
+                        msg => self.unexpected(&msg),
                     }
-                    debug!("Done receiving file");
+                    .into());
                 }
-                // send finished
-                Message::Finished.send(&mut self.stream).await?;
             }
         }
+        debug!("Done receiving file");
+        // Nothing is reported as received before it is on the disk
+        let synced = file.clone();
+        tokio::task::spawn_blocking(move || synced.sync_all()).await??;
+        self.close_file().await
     }
-    fn step(&self) -> Result<()> {
-        debug!(
-            "{}: State - {:?}",
-            if self.is_sender { "Sender" } else { "Reciever" },
-            self.state
-        );
+    /// Receives the text the sender offered as its only file into memory, the
+    /// way `receive_file` receives a file to the disk.
+    async fn receive_text(
+        &mut self,
+        receiver: &mut OwnedReceiver,
+        file_info: &FileInfo,
+        hash_algorithm: Option<HashAlgorithm>,
+    ) -> Result<String> {
+        let compression = self
+            .files_to_receive
+            .as_ref()
+            .context("No files to receive")?
+            .chunk_compression()?;
+        let size = file_info.size as usize;
+        Message::TypeRecipientReady(RemoteFileRequest {
+            files_to_transfer_current_num: 0,
+            machine_id: "".to_string(),
+            current_file_chunk_ranges: vec![],
+        })
+        .send(&mut self.stream, self.encrypted_session.as_ref())
+        .await?;
+        self.emit(ProgressEvent::FileStarted {
+            index: 0,
+            name: file_info.name.clone(),
+            size: size as u64,
+        });
+        let mut file_progress = FileProgress::new(0, size as u64);
+        let mut text = vec![0u8; size];
+        let mut current_amount = 0;
+        let mut peeked = [0u8; 1];
+        while current_amount < size {
+            tokio::select! {
+                chunk = receiver.read() => {
+                    let chunk = chunk?;
+                    if chunk == [1u8] {
+                        debug!("Got ping");
+                        continue;
+                    }
+                    let encryptor = self
+                        .encrypted_session
+                        .as_ref()
+                        .context("No encrypted session")?
+                        .as_encryptor();
+                    let (offset, data) = decode_chunk(&chunk, compression, encryptor)?;
+                    let target = usize::try_from(offset)
+                        .ok()
+                        .and_then(|start| text.get_mut(start..start.checked_add(data.len())?))
+                        .ok_or_else(|| anyhow!("Text chunk at {offset} is out of bounds"))?;
+                    target.copy_from_slice(&data);
+                    current_amount += data.len();
+                    self.transferred += data.len() as u64;
+                    self.emit(file_progress.advance(data.len() as u64));
+                }
+                // Same as in receive_file, the sender only speaks when it failed
+                amount = self.stream.peek(&mut peeked) => {
+                    if amount? == 0 {
+                        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+                    }
+                    let msg = Message::recv(&mut self.stream, self.encrypted_session.as_ref()).await?;
+                    return Err(match msg {
+                        Message::TypeError(msg) => SessionError::from_peer(msg.message),
+                        msg => self.unexpected(&msg),
+                    }
+                    .into());
+                }
+            }
+        }
+        if let Some(hash_algorithm) = hash_algorithm.filter(|_| !file_info.hash.is_empty()) {
+            file_info.verify_bytes(&text, hash_algorithm)?;
+        }
+        self.close_file().await?;
+        self.emit(ProgressEvent::FileVerified {
+            index: 0,
+            name: file_info.name.clone(),
+        });
+        // Go croc sends whatever bytes it was given as text
+        Ok(String::from_utf8_lossy(&text).into_owned())
+    }
+    /// Tells the sender the current file is done and waits for it to agree.
+    async fn close_file(&mut self) -> Result<()> {
+        // Go croc's sender only serves the next request once the file is closed
+        Message::CloseSender
+            .send(&mut self.stream, self.encrypted_session.as_ref())
+            .await?;
+        let msg = Message::recv(&mut self.stream, self.encrypted_session.as_ref()).await?;
+        self.state = self
+            .state
+            .next(false, msg.kind())
+            .ok_or_else(|| self.unexpected(&msg))?;
+        match msg {
+            Message::CloseRecipient => {}
+            Message::TypeError(msg) => return Err(SessionError::from_peer(msg.message).into()),
+            msg => return Err(self.unexpected(&msg).into()),
+        }
         Ok(())
     }
+    /// Applies the overwrite policy to a file about to be received. Returns
+    /// where it should go, or `None` when it should be skipped.
+    async fn resolve_existing(&self, file_info: &FileInfo) -> Result<Option<FileInfo>> {
+        let path = file_info.remote_path(self.base_dir())?;
+        if tokio::fs::symlink_metadata(&path).await.is_err() {
+            return Ok(Some(file_info.clone()));
+        }
+        Ok(match self.config.policy().overwrite {
+            OverwriteMode::Overwrite => Some(file_info.clone()),
+            OverwriteMode::Skip => None,
+            OverwriteMode::Rename => Some(file_info.renamed(self.base_dir()).await?),
+            OverwriteMode::Prompt => ask(self.config.interaction(), move |i| {
+                i.confirm_overwrite(&path)
+            })
+            .await?
+            .then(|| file_info.clone()),
+        })
+    }
+    /// Where received files go.
+    fn base_dir(&self) -> &Path {
+        self.config.base_dir()
+    }
+    fn unexpected(&self, message: &Message) -> SessionError {
+        SessionError::Protocol {
+            message: message.kind(),
+            state: format!("{:?}", self.state),
+        }
+    }
+    fn emit(&self, event: ProgressEvent) {
+        if let Some(progress) = &self.progress {
+            // Nobody listening anymore is not a reason to stop the transfer
+            let _ = progress.send(event);
+        }
+    }
 }
 
 impl ClientSession {
@@ -260,7 +709,8 @@ impl ClientSession {
                 error!("Protocol not supported");
                 return Err(ProtoError::CurveNotSupported.into());
             }
-            let mut key = Pake::new(Role::Reciever, Some(self.shared_secret[5..].as_bytes()));
+            let (_, pake_secret) = split_secret(&self.shared_secret)?;
+            let mut key = Pake::new(Role::Reciever, Some(pake_secret.as_bytes()));
             key.update(serde_json::from_str(&String::from_utf8(
                 msg.bytes.clone(),
             )?)?)?;
@@ -273,9 +723,8 @@ impl ClientSession {
             });
             self.key = Some(key);
             debug!("Senging to Receiver");
-            self.stream
-                .write(serde_json::to_string(&msg)?.as_bytes())
-                .await?;
+            // The session key is only derived after this reply
+            msg.send(&mut self.stream, None).await?;
         } else {
             if let Some(key) = &mut self.key {
                 key.update(serde_json::from_slice(msg.bytes.as_slice())?)?;
@@ -289,31 +738,23 @@ impl ClientSession {
             &key,
             Some(salt),
         )));
-        // Should Connect to other relay ports
-        //====================================
-        // ...
         if !self.is_sender {
             debug!("Receiver Sending IP");
             Message::ExternalIP(ExternalIPMessage {
                 external_ip: self.external_ip.clone(),
             })
-            .send(&mut self.stream)
+            .send(&mut self.stream, self.encrypted_session.as_ref())
             .await?;
         }
-        self.state = ClientState::IpExchange;
         Ok(())
         // Usually connects
     }
     async fn process_ip_exchange(&mut self, msg: ExternalIPMessage) -> Result<()> {
-        if self.state != ClientState::IpExchange {
-            // TODO: Make good error
-            return Err(anyhow!("Invalid State"));
-        }
         if self.is_sender {
             Message::ExternalIP(ExternalIPMessage {
                 external_ip: self.external_ip.clone(),
             })
-            .send(&mut self.stream)
+            .send(&mut self.stream, self.encrypted_session.as_ref())
             .await?
         }
         self.peer_external_ip = Some(msg.external_ip);
@@ -322,46 +763,49 @@ impl ClientSession {
             self.external_ip,
             self.peer_external_ip.as_ref().unwrap()
         );
-        self.state = ClientState::FileInfoTransfare;
         Ok(())
     }
-    async fn process_files_info(&mut self, files_info: FilesInformation) -> Result<()> {
-        assert!(!self.is_sender);
-        if self.state != ClientState::FileInfoTransfare {
-            // TODO: Make good error
-            return Err(anyhow!("Invalid State"));
+    async fn process_files_info(&mut self, mut files_info: FilesInformation) -> Result<()> {
+        if files_info.sending_text {
+            // Text never lands on the disk, it is received in memory and printed
+            match files_info.files_to_transfare.as_deref() {
+                Some([text]) if text.size >= 0 && text.size as usize <= MAX_TEXT_SIZE => {}
+                _ => {
+                    return Err(anyhow!(
+                        "Text must be a single file of at most {MAX_TEXT_SIZE} bytes"
+                    ))
+                }
+            }
+            files_info.empty_folders_to_transfare = None;
         }
         self.files_to_receive = Some(files_info);
         if let Some(files_info) = &self.files_to_receive {
-            // TODO: Change files to random names if `Sending Text`
-            let files_info_local = files_info.clone();
-            let confirmed = tokio::task::spawn_blocking(move || {
-                Confirm::new(&format!(
-                    "Should receive {} items ({} bytes)",
-                    files_info_local.total_items(),
-                    files_info_local.total_size()
-                ))
-                .prompt()
-                .unwrap()
-            })
-            .await?;
+            let policy = self.config.policy();
+            let total_size = files_info.total_size() as u64;
+            let confirmed = match policy.max_size {
+                Some(max_size) if total_size > max_size => {
+                    warn!("Refusing {total_size} bytes, the limit is {max_size} bytes");
+                    false
+                }
+                _ if policy.auto_accept => true,
+                _ => {
+                    let files = files_info.clone();
+                    ask(self.config.interaction(), move |i| {
+                        i.accept_transfer(&files)
+                    })
+                    .await?
+                }
+            };
             if !confirmed {
-                // Notify sender that we did not allow the transaction
-                Message::TypeError(TypeErrorMessage {
-                    message: "refusing files".to_string(),
-                })
-                .send(&mut self.stream)
-                .await?;
-                return Err(anyhow!("Transfare Denied"));
-            }
-            files_info.create_empty_folders().await?;
-            //fs_handler_transmitter.create_empty_folders().await?;
-            if files_info.files_to_transfare.is_none() {
-                Message::Finished.send(&mut self.stream).await?;
-                self.state = ClientState::FileTransfared;
-            } else {
-                self.state = ClientState::FileTransfare;
+                return Err(SessionError::Refused.into());
             }
+            self.emit(ProgressEvent::TransferStarted {
+                files: files_info.total_items(),
+                bytes: files_info.total_size() as u64,
+            });
+            files_info
+                .create_empty_folders(self.base_dir(), policy.overwrite, self.config.interaction())
+                .await?;
         }
         Ok(())
     }
@@ -371,10 +815,18 @@ impl ClientSession {
         msg: RemoteFileRequest,
         files: &FilesInformation,
     ) -> Result<()> {
-        assert!(self.is_sender);
-        assert!(self.state == ClientState::FileTransfare);
+        let compression = files.chunk_compression()?;
         if let Some(files) = &files.files_to_transfare {
-            let current_file = &files[msg.files_to_transfer_current_num as usize];
+            let current_file = msg
+                .files_to_transfer_current_num
+                .try_into()
+                .ok()
+                .and_then(|index: usize| files.get(index))
+                .context(format!(
+                    "Asked for file {} of {}",
+                    msg.files_to_transfer_current_num,
+                    files.len()
+                ))?;
             // join name and folder source using std:
             let file_path =
                 std::path::Path::new(&current_file.source_folder).join(&current_file.name);
@@ -382,28 +834,54 @@ impl ClientSession {
             let file = File::open(file_path).await?;
             // get file size
             let file_size = file.metadata().await?.len();
-            let file = Arc::new(Mutex::new(file));
-            // send chunks of the file to reader while chunk should be equals or less than TCP_BUFFER_SIZE
-            debug!("Sending chunks");
-            for chunk_offset in (0..file_size).step_by(TCP_BUFFER_SIZE as usize) {
-                let chunk_size = if chunk_offset + TCP_BUFFER_SIZE as u64 > file_size {
-                    file_size - chunk_offset
-                } else {
-                    TCP_BUFFER_SIZE as u64
-                };
-                reader
-                    .send(FileChunkInfo {
-                        file: file.clone(),
-                        chunk_size: chunk_size as usize,
-                        chunk_offset: chunk_offset as usize,
-                    })
-                    .await?;
-                debug!(
-                    "Chunk number {}/{} sent",
-                    chunk_offset / TCP_BUFFER_SIZE as u64,
-                    file_size / TCP_BUFFER_SIZE as u64
-                );
+            let file = Arc::new(file.into_std().await);
+            // send chunks of the file to reader while chunk should be equals or less than CHUNK_SIZE
+            let chunk_offsets =
+                chunk_ranges_to_offsets(&msg.current_file_chunk_ranges, file_size, CHUNK_SIZE)?;
+            debug!("Sending {} chunks", chunk_offsets.len());
+            let total_bytes = chunk_offsets
+                .iter()
+                .map(|offset| CHUNK_SIZE.min(file_size - offset))
+                .sum();
+            let index = msg.files_to_transfer_current_num as usize;
+            self.emit(ProgressEvent::FileStarted {
+                index,
+                name: current_file.name.clone(),
+                size: total_bytes,
+            });
+            let mut file_progress = FileProgress::new(index, total_bytes);
+            // The reader reports how many bytes each chunk held once it is sent
+            let (sent, mut sent_receiver) = tokio::sync::mpsc::channel(100);
+            let mut offsets = chunk_offsets.iter().copied();
+            let mut next_offset = offsets.next();
+            let mut current_amount = 0;
+            while current_amount < total_bytes {
+                tokio::select! {
+                    // Only queue more chunks while the reader keeps up
+                    permit = reader.reserve(), if next_offset.is_some() => {
+                        let chunk_offset = next_offset.unwrap();
+                        permit?.send(FileChunkInfo {
+                            file: file.clone(),
+                            chunk_size: CHUNK_SIZE.min(file_size - chunk_offset) as usize,
+                            chunk_offset: chunk_offset as usize,
+                            compression,
+                            sent: sent.clone(),
+                        });
+                        next_offset = offsets.next();
+                    }
+                    Some(amount) = sent_receiver.recv() => {
+                        let amount = amount? as u64;
+                        current_amount += amount;
+                        self.transferred += amount;
+                        self.emit(file_progress.advance(amount));
+                        debug!("Sent {current_amount}/{total_bytes}");
+                    }
+                }
             }
+            self.emit(ProgressEvent::FileSent {
+                index,
+                name: current_file.name.clone(),
+            });
             debug!("Finished sending file");
         }
         Ok(())
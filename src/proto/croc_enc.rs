@@ -31,9 +31,7 @@ impl EncryptedSession {
                 AesEncryptor::new(session_key, Some(salt.as_slice().try_into()?))
             }
         };
-        Ok(Self {
-            encryptor: encryptor,
-        })
+        Ok(Self { encryptor })
     }
     pub fn from_encryptor(encryptor: AesEncryptor) -> EncryptedSession {
         Self { encryptor }
@@ -1,29 +1,99 @@
 use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
 
-use inquire::Confirm;
 use rust_pake::pake::PakePubKey;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::convert::TryFrom;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use tempfile::{NamedTempFile, TempPath};
 use tokio::fs;
 
-use super::{AsyncCrocRead, AsyncCrocWrite, CrocProto};
+use crate::{
+    common::{
+        compress::{compress, decompress, CompressionAlgorithm},
+        config::OverwriteMode,
+        interaction::{ask, Interaction},
+    },
+    crypto::hash::{hash_bytes, hash_file, HashAlgorithm},
+};
 
-#[derive(Serialize, Deserialize, Debug)]
+use super::{croc_raw::ProtoError, AsyncCrocRead, AsyncCrocWrite, CrocProto, EncryptedSession};
+
+// How many `name (n).ext` names are tried before giving up on renaming
+const MAX_RENAMES: u32 = 10_000;
+
+/// Go encodes `[]byte` as base64 strings and `nil` slices as `null`.
+mod go_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(encoded) => STANDARD.decode(encoded).map_err(D::Error::custom),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+fn is_zero(num: &i64) -> bool {
+    *num == 0
+}
+
+/// The envelope every message travels in, `message.Message` in Go croc.
+/// The message specific payload is carried in its generic fields.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct RawMessage {
+    #[serde(rename = "t", default, skip_serializing_if = "String::is_empty")]
+    kind: String,
+    #[serde(rename = "m", default, skip_serializing_if = "String::is_empty")]
+    message: String,
+    #[serde(
+        rename = "b",
+        default,
+        with = "go_bytes",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    bytes: Vec<u8>,
+    #[serde(
+        rename = "b2",
+        default,
+        with = "go_bytes",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    bytes2: Vec<u8>,
+    #[serde(rename = "n", default, skip_serializing_if = "is_zero")]
+    num: i64,
+}
+
+#[derive(Debug, Clone)]
 pub struct PakeMessage {
-    #[serde(rename = "b")]
     pub(crate) bytes: Vec<u8>,
-    #[serde(rename = "b2")]
     pub(crate) bytes2: Vec<u8>,
 }
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug, Clone)]
 pub struct ExternalIPMessage {
-    #[serde(rename = "m")]
     pub(crate) external_ip: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct RemoteFileRequest {
-    #[serde(rename = "CurrentFileChunkRanges")]
+    #[serde(
+        rename = "CurrentFileChunkRanges",
+        deserialize_with = "null_as_default"
+    )]
     pub current_file_chunk_ranges: Vec<i64>,
     #[serde(rename = "FilesToTransferCurrentNum")]
     pub files_to_transfer_current_num: i64,
@@ -31,7 +101,8 @@ pub struct RemoteFileRequest {
     pub machine_id: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct FileInfo {
     #[serde(rename = "n")]
     pub name: String,
@@ -39,7 +110,7 @@ pub struct FileInfo {
     pub remote_folder: String,
     #[serde(rename = "fs")]
     pub source_folder: String,
-    #[serde(rename = "h")]
+    #[serde(rename = "h", with = "go_bytes")]
     pub hash: Vec<u8>,
     #[serde(rename = "s")]
     pub size: i64,
@@ -63,26 +134,319 @@ enum FileOperationError {
     TraversalError(String),
     #[error("User denide file overwrite")]
     OverwriteDenide,
+    #[error("{0} is not a regular file")]
+    NotAFile(String),
+    #[error("Hash of {0} does not match the one sent by the sender")]
+    HashMismatch(String),
+    #[error("No free name left for {0}")]
+    NoFreeName(String),
+}
+/// Whether `err` is a received file not matching the sender's hash.
+pub fn is_hash_mismatch(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref(),
+        Some(FileOperationError::HashMismatch(_))
+    )
 }
 impl FileInfo {
-    pub async fn create_folder(&self) -> Result<()> {
-        let path = fs::canonicalize(&self.remote_folder).await?;
-        if !path.starts_with(std::env::current_dir()?) {
-            warn!("Path {path:?} is outside of current directory.");
-            return Err(
-                FileOperationError::TraversalError(path.to_str().unwrap().to_string()).into(),
-            );
+    /// Describes a single local file so it can be offered to the receiver,
+    /// `remote_folder` is where the receiver should place it.
+    pub async fn from_path(
+        path: &Path,
+        remote_folder: String,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<FileInfo> {
+        let metadata = fs::symlink_metadata(path).await?;
+        if metadata.is_dir() {
+            return Err(FileOperationError::NotAFile(path.display().to_string()).into());
+        }
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .context(format!("Invalid file name {path:?}"))?
+            .to_string();
+        let modification_time = DateTime::<Utc>::from(metadata.modified()?)
+            .to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        // Symlinks are recreated on the other side, nothing of them is transferred
+        if metadata.is_symlink() {
+            let target = fs::read_link(path).await?;
+            return Ok(FileInfo {
+                name,
+                remote_folder,
+                source_folder: source_folder(path)?,
+                modification_time,
+                symlink: target
+                    .to_str()
+                    .context(format!("Invalid symlink target {target:?}"))?
+                    .to_string(),
+                mode: file_mode(&metadata),
+                ..Default::default()
+            });
+        }
+        Ok(FileInfo {
+            name,
+            remote_folder,
+            source_folder: source_folder(path)?,
+            hash: hash_file(path, hash_algorithm).await?,
+            size: metadata.len() as i64,
+            modification_time,
+            is_compressed: false,
+            is_encrypted: false,
+            symlink: "".to_string(),
+            mode: file_mode(&metadata),
+            temp_file: false,
+        })
+    }
+    /// Describes an empty local folder, only the folder fields are meaningful.
+    fn empty_folder(path: &Path, remote_folder: String) -> Result<FileInfo> {
+        Ok(FileInfo {
+            name: "".to_string(),
+            remote_folder,
+            source_folder: path.to_str().context("Invalid source folder")?.to_string(),
+            hash: vec![],
+            size: 0,
+            modification_time: "".to_string(),
+            is_compressed: false,
+            is_encrypted: false,
+            symlink: "".to_string(),
+            mode: 0,
+            temp_file: false,
+        })
+    }
+    /// The folder the receiver should write this file to, under `base`.
+    /// Refuses folders that are absolute or climb out of `base`.
+    pub fn remote_folder_path(&self, base: &Path) -> Result<PathBuf> {
+        let path = Path::new(&self.remote_folder);
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            warn!("Path {path:?} tries to escape the destination folder.");
+            return Err(FileOperationError::TraversalError(self.remote_folder.clone()).into());
+        }
+        Ok(base.join(path))
+    }
+    /// Where the receiver should write this file, under `base`. The name must
+    /// be a plain file name.
+    pub fn remote_path(&self, base: &Path) -> Result<PathBuf> {
+        let mut components = Path::new(&self.name).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if name == self.name.as_str() => {}
+            _ => {
+                warn!("Name {:?} is not a plain file name.", self.name);
+                return Err(FileOperationError::TraversalError(self.name.clone()).into());
+            }
+        }
+        Ok(self.remote_folder_path(base)?.join(&self.name))
+    }
+    /// Checks the received file against the hash the sender computed.
+    pub async fn verify(&self, base: &Path, hash_algorithm: HashAlgorithm) -> Result<()> {
+        let path = self.remote_path(base)?;
+        if hash_file(&path, hash_algorithm).await? != self.hash {
+            error!("Hash mismatch for {path:?}");
+            return Err(FileOperationError::HashMismatch(path.display().to_string()).into());
+        }
+        debug!("Verified {path:?} using {hash_algorithm}");
+        Ok(())
+    }
+    /// Checks `data` received for this file against the sender's hash.
+    pub fn verify_bytes(&self, data: &[u8], hash_algorithm: HashAlgorithm) -> Result<()> {
+        if hash_bytes(data, hash_algorithm)? != self.hash {
+            error!("Hash mismatch for {:?}", self.name);
+            return Err(FileOperationError::HashMismatch(self.name.clone()).into());
+        }
+        Ok(())
+    }
+    /// Whether the destination already holds this exact file, so there is no
+    /// need to request it again.
+    pub async fn already_received(
+        &self,
+        base: &Path,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<bool> {
+        let path = self.remote_path(base)?;
+        if !self.symlink.is_empty() {
+            return Ok(fs::read_link(&path)
+                .await
+                .is_ok_and(|target| target == Path::new(&self.symlink)));
         }
+        // A link in its place is not the file, even when it points to a copy
+        match fs::symlink_metadata(&path).await {
+            Ok(metadata)
+                if metadata.is_file()
+                    && metadata.len() == self.size as u64
+                    && !self.hash.is_empty() =>
+            {
+                Ok(hash_file(&path, hash_algorithm).await? == self.hash)
+            }
+            _ => Ok(false),
+        }
+    }
+    /// Gives the received file the sender's modification time and permissions.
+    pub async fn apply_metadata(&self, base: &Path) -> Result<()> {
+        let path = self.remote_path(base)?;
+        match DateTime::parse_from_rfc3339(&self.modification_time) {
+            Ok(modification_time) => {
+                let file = fs::File::open(&path).await?.into_std().await;
+                tokio::task::spawn_blocking(move || file.set_modified(modification_time.into()))
+                    .await??;
+            }
+            Err(err) => debug!("Keeping the modification time of {path:?}: {err}"),
+        }
+        // Set last, the mode may make the file read only
+        if self.mode != 0 {
+            set_file_mode(&path, self.mode).await?;
+        }
+        Ok(())
+    }
+    /// Recreates the symlink the sender had in place of this file, replacing
+    /// whatever is there (see `ClientSession` for the overwrite policy).
+    pub async fn create_symlink(&self, base: &Path) -> Result<()> {
+        let path = self.remote_path(base)?;
+        let target = self.symlink_target()?;
+        fs::create_dir_all(self.remote_folder_path(base)?).await?;
+        match fs::symlink_metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(FileOperationError::OverwriteDenide.into())
+            }
+            Ok(_) => fs::remove_file(&path).await?,
+            Err(_) => {}
+        }
+        create_symlink(target, &path).await?;
+        Ok(())
+    }
+    /// The symlink target, which must be relative and stay under the
+    /// destination once resolved against the folder of the link.
+    fn symlink_target(&self) -> Result<&Path> {
+        let target = Path::new(&self.symlink);
+        // How deep under the destination the folder of the link is
+        let mut depth = Path::new(&self.remote_folder)
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .count();
+        for component in target.components() {
+            depth = match component {
+                Component::Normal(_) => depth + 1,
+                Component::CurDir => depth,
+                Component::ParentDir if depth > 0 => depth - 1,
+                _ => {
+                    warn!("Symlink target {target:?} leaves the destination folder.");
+                    return Err(FileOperationError::TraversalError(self.symlink.clone()).into());
+                }
+            };
+        }
+        Ok(target)
+    }
+    pub async fn create_folder(
+        &self,
+        base: &Path,
+        overwrite: OverwriteMode,
+        interaction: &Arc<dyn Interaction>,
+    ) -> Result<()> {
+        let path = self.remote_folder_path(base)?;
         if path.exists() {
-            if !Confirm::new(&format!("{path:?} exists, do you want to overwrite?")).prompt()? {
-                return Err(FileOperationError::OverwriteDenide.into());
+            let asked = path.clone();
+            match overwrite {
+                OverwriteMode::Prompt
+                    if !ask(interaction, move |i| i.confirm_overwrite(&asked)).await? =>
+                {
+                    return Err(FileOperationError::OverwriteDenide.into());
+                }
+                // An empty folder that is already there has nothing to lose
+                _ => return Ok(()),
             }
         }
-        fs::create_dir(path).await?;
+        fs::create_dir_all(path).await?;
         Ok(())
     }
+    /// The same file under the first free `name (n).ext` in its folder.
+    pub async fn renamed(&self, base: &Path) -> Result<FileInfo> {
+        let name = Path::new(&self.name);
+        let stem = name
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(&self.name);
+        let extension = name
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| format!(".{extension}"))
+            .unwrap_or_default();
+        let folder = self.remote_folder_path(base)?;
+        for number in 1..=MAX_RENAMES {
+            let candidate = format!("{stem} ({number}){extension}");
+            if fs::symlink_metadata(folder.join(&candidate)).await.is_err() {
+                return Ok(FileInfo {
+                    name: candidate,
+                    ..self.clone()
+                });
+            }
+        }
+        Err(FileOperationError::NoFreeName(self.name.clone()).into())
+    }
+}
+
+/// The permission bits of a file, what Go's `FileMode` holds for regular files.
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o777
+}
+#[cfg(not(unix))]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o666
+    }
+}
+
+#[cfg(unix)]
+async fn set_file_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777)).await?;
+    Ok(())
+}
+#[cfg(not(unix))]
+async fn set_file_mode(path: &Path, mode: u32) -> Result<()> {
+    let mut permissions = fs::metadata(path).await?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions).await?;
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    fs::symlink(target, path).await?;
+    Ok(())
+}
+#[cfg(windows)]
+async fn create_symlink(target: &Path, path: &Path) -> Result<()> {
+    fs::symlink_file(target, path).await?;
+    Ok(())
+}
+
+fn source_folder(path: &Path) -> Result<String> {
+    Ok(match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => ".".to_string(),
+        Some(parent) => parent
+            .to_str()
+            .context("Invalid source folder")?
+            .to_string(),
+        None => ".".to_string(),
+    })
+}
+
+/// Joins `base` and `name` the way croc does on the wire, always with '/'.
+fn join_remote(base: &str, name: &str) -> String {
+    if base == "." {
+        name.to_string()
+    } else {
+        format!("{base}/{name}")
+    }
 }
-#[derive(Serialize, Deserialize, Debug, Clone)]
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct FilesInformation {
     #[serde(rename = "FilesToTransfer")]
     pub files_to_transfare: Option<Vec<FileInfo>>,
@@ -100,39 +464,176 @@ pub struct FilesInformation {
     pub no_compress: bool,
     #[serde(rename = "HashAlgorithm")]
     pub hash_algorithm: String,
+    // Not part of Go croc, which always uses flate. Left out when empty so Go
+    // peers see the same JSON.
+    #[serde(rename = "Compression", skip_serializing_if = "String::is_empty")]
+    pub compression: String,
+    // Not part of Go croc either, tells the receiver to only join the first
+    // data room. Go receivers join all of them, which still works.
+    #[serde(rename = "NoMultiplexing", skip_serializing_if = "is_false")]
+    pub no_multiplexing: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
 }
 
 impl FilesInformation {
+    pub async fn from_paths(
+        paths: &[PathBuf],
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<FilesInformation> {
+        let mut files = vec![];
+        let mut empty_folders = vec![];
+        let mut total_folders_number = 0;
+        for path in paths {
+            if fs::metadata(path).await?.is_dir() {
+                total_folders_number +=
+                    walk_folder(path, hash_algorithm, &mut files, &mut empty_folders).await?;
+            } else {
+                files.push(FileInfo::from_path(path, ".".to_string(), hash_algorithm).await?);
+            }
+        }
+        Ok(FilesInformation {
+            files_to_transfare: Some(files),
+            empty_folders_to_transfare: Some(empty_folders),
+            total_folders_number,
+            machine_id: "".to_string(),
+            ask: false,
+            sending_text: false,
+            no_compress: false,
+            hash_algorithm: hash_algorithm.to_string(),
+            compression: "".to_string(),
+            no_multiplexing: false,
+        }
+        .with_compression(Some(CompressionAlgorithm::Flate)))
+    }
+    /// Offers `text` as a single temporary file, the receiver prints it
+    /// instead of keeping it. The file is removed once the returned path is dropped.
+    pub async fn from_text(
+        text: &str,
+        hash_algorithm: HashAlgorithm,
+    ) -> Result<(FilesInformation, TempPath)> {
+        let path = NamedTempFile::new()?.into_temp_path();
+        fs::write(&path, text).await?;
+        let mut file = FileInfo::from_path(&path, ".".to_string(), hash_algorithm).await?;
+        file.temp_file = true;
+        let files = FilesInformation {
+            files_to_transfare: Some(vec![file]),
+            empty_folders_to_transfare: Some(vec![]),
+            sending_text: true,
+            hash_algorithm: hash_algorithm.to_string(),
+            ..Default::default()
+        }
+        .with_compression(Some(CompressionAlgorithm::Flate));
+        Ok((files, path))
+    }
+    /// Compresses chunks with `compression`, or not at all when `None`.
+    pub fn with_compression(mut self, compression: Option<CompressionAlgorithm>) -> Self {
+        self.no_compress = compression.is_none();
+        for file in self.files_to_transfare.iter_mut().flatten() {
+            file.is_compressed = compression.is_some();
+        }
+        self.compression = match compression {
+            Some(CompressionAlgorithm::Flate) | None => "".to_string(),
+            Some(compression) => compression.to_string(),
+        };
+        self
+    }
+    /// How the sender compresses chunks, `None` when it doesn't.
+    pub fn chunk_compression(&self) -> Result<Option<CompressionAlgorithm>> {
+        if self.no_compress {
+            return Ok(None);
+        }
+        if self.compression.is_empty() {
+            return Ok(Some(CompressionAlgorithm::Flate));
+        }
+        Ok(Some(self.compression.parse()?))
+    }
     pub fn total_items(&self) -> usize {
-        return self.files_to_transfare.as_ref().map_or(0, |vec| vec.len())
+        self.files_to_transfare.as_ref().map_or(0, |vec| vec.len())
             + self
                 .empty_folders_to_transfare
                 .as_ref()
-                .map_or(0, |vec| vec.len());
+                .map_or(0, |vec| vec.len())
     }
     pub fn total_size(&self) -> i64 {
         // Can have integer overflow here.
-        return self
-            .files_to_transfare
+        self.files_to_transfare
             .as_ref()
-            .map_or(0, |vec| vec.iter().map(|val| val.size).sum());
+            .map_or(0, |vec| vec.iter().map(|val| val.size).sum())
     }
-    pub async fn create_empty_folders(&self) -> Result<()> {
+    pub async fn create_empty_folders(
+        &self,
+        base: &Path,
+        overwrite: OverwriteMode,
+        interaction: &Arc<dyn Interaction>,
+    ) -> Result<()> {
         for file in self.empty_folders_to_transfare.as_ref().unwrap_or(&vec![]) {
-            file.create_folder().await?;
+            file.create_folder(base, overwrite, interaction).await?;
         }
         Ok(())
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Walks `root` and collects every file in it with a remote folder relative to
+/// the parent of `root`, so `send some_dir/` recreates `some_dir` on the other side.
+/// Returns the amount of folders found.
+async fn walk_folder(
+    root: &Path,
+    hash_algorithm: HashAlgorithm,
+    files: &mut Vec<FileInfo>,
+    empty_folders: &mut Vec<FileInfo>,
+) -> Result<usize> {
+    let root_name = fs::canonicalize(root)
+        .await?
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context(format!("Invalid folder name {root:?}"))?
+        .to_string();
+    let mut folders = 0;
+    let mut pending = vec![(root.to_path_buf(), root_name)];
+    while let Some((folder, remote_folder)) = pending.pop() {
+        folders += 1;
+        let mut entries = vec![];
+        let mut dir = fs::read_dir(&folder).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            entries.push(entry.path());
+        }
+        if entries.is_empty() {
+            empty_folders.push(FileInfo::empty_folder(&folder, remote_folder)?);
+            continue;
+        }
+        entries.sort();
+        let mut sub_folders = vec![];
+        for entry in entries {
+            let name = entry
+                .file_name()
+                .and_then(|name| name.to_str())
+                .context(format!("Invalid file name {entry:?}"))?
+                .to_string();
+            let metadata = fs::symlink_metadata(&entry).await?;
+            if metadata.is_dir() {
+                sub_folders.push((entry, join_remote(&remote_folder, &name)));
+            } else {
+                files.push(
+                    FileInfo::from_path(&entry, remote_folder.clone(), hash_algorithm).await?,
+                );
+            }
+        }
+        // Depth first, in name order
+        pending.extend(sub_folders.into_iter().rev());
+    }
+    Ok(folders)
+}
 
+#[derive(Debug, Clone)]
 pub struct TypeErrorMessage {
-    #[serde(rename = "m")]
     pub(crate) message: String,
 }
 
 impl PakeMessage {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(pub_key: &PakePubKey, curve_type: String) -> Result<Message> {
         Ok(Message::Pake(PakeMessage {
             bytes: serde_json::to_string(&pub_key)?.into(),
@@ -141,42 +642,322 @@ impl PakeMessage {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "t")]
+#[derive(Debug, Clone)]
 pub enum Message {
-    #[serde(rename = "pake")]
     Pake(PakeMessage),
-    #[serde(rename = "externalip")]
     ExternalIP(ExternalIPMessage),
-    #[serde(rename = "fileinfo")]
     FilesInfo(FilesInformation),
-    #[serde(rename = "recipientready")]
     TypeRecipientReady(RemoteFileRequest),
-    #[serde(rename = "error")]
     TypeError(TypeErrorMessage),
-    #[serde(rename = "finished")]
     Finished,
+    /// Go croc's receiver sends it once it has a whole file, it waits for the
+    /// sender's `CloseRecipient` before asking for the next one.
+    CloseSender,
+    CloseRecipient,
 }
 impl TryFrom<&[u8]> for Message {
-    type Error = serde_json::Error;
+    type Error = anyhow::Error;
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        serde_json::from_slice(value)
+        Message::from_raw(serde_json::from_slice(value)?)
     }
 }
 impl Message {
-    pub async fn send(&self, conn: &mut CrocProto) -> Result<()>
-    where
-        Self: Serialize,
-    {
-        conn.write(serde_json::to_string(&self)?.as_bytes()).await
-    }
-    pub async fn recv(conn: &mut CrocProto) -> Result<Self>
-    where
-        Self: DeserializeOwned,
-    {
-        let test = conn.read().await?;
-        let bytes_read = &String::from_utf8(test.clone())?;
-        Ok(serde_json::from_str(bytes_read)
-            .context(format!("Could not parse received data: {test:?}"))?)
+    /// The message type, as Go croc names it on the wire.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Pake(_) => "pake",
+            Message::ExternalIP(_) => "externalip",
+            Message::FilesInfo(_) => "fileinfo",
+            Message::TypeRecipientReady(_) => "recipientready",
+            Message::TypeError(_) => "error",
+            Message::Finished => "finished",
+            Message::CloseSender => "close-sender",
+            Message::CloseRecipient => "close-recipient",
+        }
+    }
+    fn to_raw(&self) -> Result<RawMessage> {
+        let raw = match self {
+            Message::Pake(msg) => RawMessage {
+                bytes: msg.bytes.clone(),
+                bytes2: msg.bytes2.clone(),
+                ..Default::default()
+            },
+            Message::ExternalIP(msg) => RawMessage {
+                message: msg.external_ip.clone(),
+                ..Default::default()
+            },
+            Message::FilesInfo(files) => RawMessage {
+                bytes: serde_json::to_vec(files)?,
+                ..Default::default()
+            },
+            Message::TypeRecipientReady(request) => RawMessage {
+                bytes: serde_json::to_vec(request)?,
+                ..Default::default()
+            },
+            Message::TypeError(msg) => RawMessage {
+                message: msg.message.clone(),
+                ..Default::default()
+            },
+            Message::Finished | Message::CloseSender | Message::CloseRecipient => {
+                RawMessage::default()
+            }
+        };
+        Ok(RawMessage {
+            kind: self.kind().to_string(),
+            ..raw
+        })
+    }
+    fn from_raw(raw: RawMessage) -> Result<Message> {
+        Ok(match raw.kind.as_str() {
+            "pake" => Message::Pake(PakeMessage {
+                bytes: raw.bytes,
+                bytes2: raw.bytes2,
+            }),
+            "externalip" => Message::ExternalIP(ExternalIPMessage {
+                external_ip: raw.message,
+            }),
+            "fileinfo" => Message::FilesInfo(serde_json::from_slice(&raw.bytes)?),
+            "recipientready" => Message::TypeRecipientReady(serde_json::from_slice(&raw.bytes)?),
+            "error" => Message::TypeError(TypeErrorMessage {
+                message: raw.message,
+            }),
+            "finished" => Message::Finished,
+            "close-sender" => Message::CloseSender,
+            "close-recipient" => Message::CloseRecipient,
+            kind => return Err(ProtoError::UnknownMessageType(kind.to_string()).into()),
+        })
+    }
+    /// Serializes the message like Go croc does: JSON, flate compressed and
+    /// encrypted once the session key was agreed on.
+    pub fn encode(&self, session: Option<&EncryptedSession>) -> Result<Vec<u8>> {
+        let data = compress(&serde_json::to_vec(&self.to_raw()?)?)?;
+        match session {
+            Some(session) => session.as_encryptor().encrypt(&data),
+            None => Ok(data),
+        }
+    }
+    pub fn decode(data: &[u8], session: Option<&EncryptedSession>) -> Result<Message> {
+        let data = match session {
+            Some(session) => session.as_encryptor().decrypt(data)?,
+            None => data.to_vec(),
+        };
+        let data = decompress(&data)?;
+        Message::try_from(data.as_slice())
+            .context(format!("Could not parse received data: {data:?}"))
+    }
+    pub async fn send(
+        &self,
+        conn: &mut CrocProto,
+        session: Option<&EncryptedSession>,
+    ) -> Result<()> {
+        conn.write(&self.encode(session)?).await
+    }
+    pub async fn recv(conn: &mut CrocProto, session: Option<&EncryptedSession>) -> Result<Self> {
+        Message::decode(&conn.read().await?, session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use std::path::Path;
+
+    use super::{
+        FileInfo, FileOperationError, FilesInformation, Message, RemoteFileRequest, MAX_RENAMES,
+    };
+    use crate::{
+        common::compress::{compress, decompress},
+        crypto::aes::AesEncryptor,
+        proto::EncryptedSession,
+    };
+
+    // The way Go croc v10 marshals a fileinfo message, nil slices included
+    const GO_FILES_INFO: &str = r#"{"FilesToTransfer":[{"n":"hello.txt","fr":".","fs":"/tmp","h":"q83v","s":5,"m":"2024-01-02T03:04:05Z","c":false,"e":false,"sy":"","md":420,"tf":false}],"EmptyFoldersToTransfer":null,"TotalNumberFolders":0,"MachineID":"","Ask":false,"SendingText":false,"NoCompress":false,"HashAlgorithm":"xxhash"}"#;
+
+    #[test]
+    fn test_decode_go_files_info() {
+        let envelope = format!(
+            r#"{{"t":"fileinfo","b":"{}"}}"#,
+            STANDARD.encode(GO_FILES_INFO)
+        );
+        let Message::FilesInfo(files) =
+            Message::decode(&compress(envelope.as_bytes()).unwrap(), None).unwrap()
+        else {
+            panic!("Expected a fileinfo message");
+        };
+        let file = &files.files_to_transfare.unwrap()[0];
+        assert_eq!(file.name, "hello.txt");
+        assert_eq!(file.hash, vec![0xab, 0xcd, 0xef]);
+        assert_eq!(file.mode, 420);
+        assert!(files.empty_folders_to_transfare.is_none());
+        assert_eq!(files.hash_algorithm, "xxhash");
+        assert!(!files.no_multiplexing);
+
+        // Go leaves the ranges nil when the whole file is wanted
+        let envelope = format!(
+            r#"{{"t":"recipientready","b":"{}"}}"#,
+            STANDARD.encode(
+                r#"{"CurrentFileChunkRanges":null,"FilesToTransferCurrentNum":0,"MachineID":""}"#
+            )
+        );
+        let Message::TypeRecipientReady(request) =
+            Message::decode(&compress(envelope.as_bytes()).unwrap(), None).unwrap()
+        else {
+            panic!("Expected a recipientready message");
+        };
+        assert!(request.current_file_chunk_ranges.is_empty());
+    }
+
+    #[test]
+    fn test_encode_go_envelope() {
+        let session = EncryptedSession::from_encryptor(AesEncryptor::new(&[7u8; 32], None));
+        let message = Message::TypeRecipientReady(RemoteFileRequest {
+            current_file_chunk_ranges: vec![32768, 0, 1],
+            ..Default::default()
+        });
+        let encoded = message.encode(Some(&session)).unwrap();
+
+        let json = decompress(&session.as_encryptor().decrypt(&encoded).unwrap()).unwrap();
+        let envelope: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let envelope = envelope.as_object().unwrap();
+        // Empty fields are left out like Go's omitempty does
+        assert_eq!(envelope.len(), 2);
+        assert_eq!(envelope["t"], "recipientready");
+        let request: serde_json::Value =
+            serde_json::from_slice(&STANDARD.decode(envelope["b"].as_str().unwrap()).unwrap())
+                .unwrap();
+        assert_eq!(
+            request["CurrentFileChunkRanges"],
+            serde_json::json!([32768, 0, 1])
+        );
+
+        let Message::TypeRecipientReady(decoded) =
+            Message::decode(&encoded, Some(&session)).unwrap()
+        else {
+            panic!("Expected a recipientready message");
+        };
+        assert_eq!(decoded.current_file_chunk_ranges, vec![32768, 0, 1]);
+    }
+
+    #[test]
+    fn test_encode_no_multiplexing() {
+        // Only there when the sender turned multiplexing off
+        let json = serde_json::to_string(&FilesInformation::default()).unwrap();
+        assert!(!json.contains("NoMultiplexing"));
+        let json = serde_json::to_string(&FilesInformation {
+            no_multiplexing: true,
+            ..Default::default()
+        })
+        .unwrap();
+        let files: FilesInformation = serde_json::from_str(&json).unwrap();
+        assert!(files.no_multiplexing);
+    }
+
+    #[tokio::test]
+    async fn test_renamed_gives_up() {
+        let base = tempfile::tempdir().unwrap();
+        let file = FileInfo {
+            remote_folder: ".".to_string(),
+            name: "a.txt".to_string(),
+            ..Default::default()
+        };
+        std::fs::write(base.path().join("a.txt"), "").unwrap();
+        assert_eq!(file.renamed(base.path()).await.unwrap().name, "a (1).txt");
+        for number in 1..=MAX_RENAMES {
+            std::fs::write(base.path().join(format!("a ({number}).txt")), "").unwrap();
+        }
+        let err = file.renamed(base.path()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(FileOperationError::NoFreeName(_))
+        ));
+    }
+
+    #[test]
+    fn test_symlink_target() {
+        let link = |remote_folder: &str, symlink: &str| FileInfo {
+            remote_folder: remote_folder.to_string(),
+            name: "link".to_string(),
+            symlink: symlink.to_string(),
+            ..Default::default()
+        };
+        for (remote_folder, symlink) in [
+            (".", "a.txt"),
+            ("./tree/sub", "../a.txt"),
+            ("tree", "./sub/../.."),
+        ] {
+            assert!(link(remote_folder, symlink).symlink_target().is_ok());
+        }
+        for (remote_folder, symlink) in [
+            (".", "/etc/passwd"),
+            (".", ".."),
+            ("tree", "../../a.txt"),
+            ("tree", "sub/../../.."),
+        ] {
+            assert!(
+                link(remote_folder, symlink).symlink_target().is_err(),
+                "{} in {} was accepted",
+                symlink,
+                remote_folder
+            );
+        }
+    }
+
+    #[test]
+    fn test_remote_path() {
+        let base = Path::new("/srv/incoming");
+        let file = |remote_folder: &str, name: &str| FileInfo {
+            remote_folder: remote_folder.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            file("./tree/sub", "a.txt").remote_path(base).unwrap(),
+            base.join("tree/sub/a.txt")
+        );
+        for (remote_folder, name) in [
+            ("/etc", "passwd"),
+            ("../..", "a.txt"),
+            ("tree/../../x", "a.txt"),
+            (".", "../a.txt"),
+            (".", "sub/a.txt"),
+            (".", "/etc/passwd"),
+            (".", ".."),
+            (".", ""),
+        ] {
+            assert!(
+                file(remote_folder, name).remote_path(base).is_err(),
+                "{}/{} was accepted",
+                remote_folder,
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_go_close_messages() {
+        let session = EncryptedSession::from_encryptor(AesEncryptor::new(&[7u8; 32], None));
+        for (envelope, kind) in [
+            (r#"{"t":"close-sender"}"#, "close-sender"),
+            (r#"{"t":"close-recipient"}"#, "close-recipient"),
+        ] {
+            let data = session
+                .as_encryptor()
+                .encrypt(&compress(envelope.as_bytes()).unwrap())
+                .unwrap();
+            let message = Message::decode(&data, Some(&session)).unwrap();
+            assert_eq!(message.kind(), kind);
+            assert_eq!(
+                message.encode(None).unwrap(),
+                compress(envelope.as_bytes()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_unknown_message_type() {
+        let data = compress(br#"{"t":"close-everything"}"#).unwrap();
+        assert!(Message::decode(&data, None).is_err());
     }
 }
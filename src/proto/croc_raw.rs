@@ -8,8 +8,6 @@ use tokio::{
     net::{TcpStream, ToSocketAddrs},
 };
 
-
-
 #[derive(thiserror::Error, Debug)]
 pub enum ProtoError {
     #[error("Symmetric Key negotiation failed")]
@@ -18,8 +16,16 @@ pub enum ProtoError {
     CurveNotSupported,
     #[error("Curve was not initialized")]
     CurveNotInitialized,
+    #[error("Received a message of unknown type {0}")]
+    UnknownMessageType(String),
+    #[error("The relay refused the connection: {0}")]
+    Refused(String),
+    #[error("Bad magic {0:?}")]
+    BadMagic([u8; 4]),
 }
 const CROC_MAGIC: &[u8; 4] = b"croc";
+/// Starts the frames a relay refuses clients with, the rest is the reason.
+pub const ERROR_PREFIX: &[u8] = b"error: ";
 pub trait AsyncCrocRead {
     async fn read(&mut self) -> Result<Vec<u8>>;
 }
@@ -35,6 +41,24 @@ impl AsyncCrocRead for OwnedReceiver {
         self.receiver.recv().await.ok_or(anyhow!("Channel closed"))
     }
 }
+impl OwnedReceiver {
+    /// Reads from all `receivers` at once, the merged receiver is closed once
+    /// all of them are.
+    pub fn merge(receivers: Vec<OwnedReceiver>) -> OwnedReceiver {
+        let (sender, receiver) = tokio::sync::mpsc::channel(100);
+        for mut owned in receivers {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                while let Some(msg) = owned.receiver.recv().await {
+                    if sender.send(msg).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        OwnedReceiver { receiver }
+    }
+}
 
 #[derive(Clone)]
 pub struct OwnedSender {
@@ -45,7 +69,7 @@ impl AsyncCrocWrite for OwnedSender {
         let mut buffer = vec![];
         std::io::Write::write_all(&mut buffer, CROC_MAGIC)?;
         byteorder::WriteBytesExt::write_u32::<LittleEndian>(&mut buffer, msg.len().try_into()?)?;
-        std::io::Write::write_all(&mut buffer, &msg)?;
+        std::io::Write::write_all(&mut buffer, msg)?;
         self.sender.send(buffer.to_vec()).await?;
         Ok(())
     }
@@ -134,7 +158,7 @@ impl AsyncCrocWrite for MpscCrocProto {
         let mut buffer = vec![];
         std::io::Write::write_all(&mut buffer, CROC_MAGIC)?;
         byteorder::WriteBytesExt::write_u32::<LittleEndian>(&mut buffer, msg.len().try_into()?)?;
-        std::io::Write::write_all(&mut buffer, &msg)?;
+        std::io::Write::write_all(&mut buffer, msg)?;
         self.sender.send(buffer.to_vec()).await?;
         Ok(())
     }
@@ -146,9 +170,7 @@ pub struct CrocProto {
 impl CrocProto {
     #![allow(dead_code)]
     pub fn from_stream(connection: TcpStream) -> Self {
-        CrocProto {
-            connection: connection,
-        }
+        CrocProto { connection }
     }
     pub async fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
         Ok(CrocProto {
@@ -172,8 +194,12 @@ impl CrocProto {
                 self.write(serde_json::to_string(&a_key.pub_pake)?.as_bytes())
                     .await?;
 
-                let b_key: PakePubKey =
-                    serde_json::from_str(std::str::from_utf8(self.read().await?.as_slice())?)?;
+                let reply = self.read().await?;
+                // A busy relay refuses before spending time on the key exchange
+                if let Some(reason) = reply.strip_prefix(ERROR_PREFIX) {
+                    return Err(ProtoError::Refused(String::from_utf8_lossy(reason).into()).into());
+                }
+                let b_key: PakePubKey = serde_json::from_str(std::str::from_utf8(&reply)?)?;
                 debug!(
                     "sender b_key: {}",
                     serde_json::to_string_pretty(&b_key).unwrap()
@@ -212,7 +238,7 @@ impl AsyncCrocRead for CrocProto {
             .await
             .context("Could not read magic")?;
         if &header_magic != CROC_MAGIC {
-            return Err(anyhow!("Bad magic {:?}", header_magic));
+            return Err(ProtoError::BadMagic(header_magic).into());
         }
         let msg_len = self
             .connection
@@ -229,7 +255,7 @@ impl AsyncCrocWrite for CrocProto {
         let mut buffer = vec![];
         std::io::Write::write_all(&mut buffer, CROC_MAGIC)?;
         byteorder::WriteBytesExt::write_u32::<LittleEndian>(&mut buffer, msg.len().try_into()?)?;
-        std::io::Write::write_all(&mut buffer, &msg)?;
+        std::io::Write::write_all(&mut buffer, msg)?;
         self.connection
             .write_all(&buffer)
             .await
@@ -2,8 +2,12 @@ pub mod client_session;
 mod croc_enc;
 mod croc_msg;
 mod croc_raw;
+mod progress;
+mod session_state;
 pub use croc_enc::EncryptedSession;
+#[allow(unused_imports)]
 pub use croc_msg::{FileInfo, FilesInformation};
 pub use croc_raw::{
-    AsyncCrocRead, AsyncCrocWrite, CrocProto, MpscCrocProto, OwnedSender,
+    AsyncCrocRead, AsyncCrocWrite, CrocProto, MpscCrocProto, OwnedSender, ProtoError, ERROR_PREFIX,
 };
+pub use progress::ProgressEvent;
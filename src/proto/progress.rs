@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+
+/// What a `ClientSession` reports while it transfers files, see
+/// `ClientSession::with_progress`. `index` is the position of the file in
+/// `FilesInformation::files_to_transfare`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    /// The files to transfer are known, `bytes` is their total size.
+    TransferStarted { files: usize, bytes: u64 },
+    /// A file is about to be sent or received, `size` is how much of it will
+    /// go over the wire (less than the file when resuming).
+    FileStarted {
+        index: usize,
+        name: String,
+        size: u64,
+    },
+    /// The receiver already has this file.
+    FileSkipped {
+        index: usize,
+        name: String,
+        size: u64,
+    },
+    /// More of the current file was sent or written.
+    Bytes {
+        index: usize,
+        done: u64,
+        total: u64,
+        bytes_per_second: f64,
+    },
+    /// All of the file was handed to the data connections.
+    FileSent { index: usize, name: String },
+    /// The file was received and its hash matches.
+    FileVerified { index: usize, name: String },
+    /// The sender sent text instead of files, it is not kept anywhere.
+    TextReceived { text: String },
+    /// Both sides are done.
+    TransferFinished { bytes: u64, elapsed: Duration },
+}
+
+/// Tracks how far a single file got, to build `ProgressEvent::Bytes`.
+pub(crate) struct FileProgress {
+    index: usize,
+    done: u64,
+    total: u64,
+    started: Instant,
+}
+
+impl FileProgress {
+    pub fn new(index: usize, total: u64) -> Self {
+        Self {
+            index,
+            done: 0,
+            total,
+            started: Instant::now(),
+        }
+    }
+    pub fn advance(&mut self, amount: u64) -> ProgressEvent {
+        self.done += amount;
+        let elapsed = self.started.elapsed().as_secs_f64();
+        ProgressEvent::Bytes {
+            index: self.index,
+            done: self.done,
+            total: self.total,
+            bytes_per_second: if elapsed > 0.0 {
+                self.done as f64 / elapsed
+            } else {
+                0.0
+            },
+        }
+    }
+}
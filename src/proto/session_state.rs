@@ -0,0 +1,162 @@
+/// Where a session is in the protocol. Every received message goes through
+/// [`ClientState::next`], anything it does not allow ends the session.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClientState {
+    /// Waiting for the peer's PAKE message.
+    #[default]
+    KeyExchange,
+    /// Keys are agreed on, waiting for the peer's external IP.
+    IpExchange,
+    /// The receiver waits for the list of files.
+    FileInfoTransfare,
+    /// The sender sent the list of files and serves the receiver's requests.
+    FileTransfare,
+    /// The receiver got every file and said it is finished.
+    FileTransfared,
+    /// Nothing more is expected.
+    Closed,
+}
+
+impl ClientState {
+    /// The state after receiving a message of type `kind`, or `None` when it
+    /// is out of order. The sender's reply to the IP exchange carries the
+    /// files, and the receiver receives all of them before it goes on, so
+    /// both skip the states they pass on their own.
+    pub(crate) fn next(self, is_sender: bool, kind: &str) -> Option<ClientState> {
+        use ClientState::*;
+        Some(match (is_sender, self, kind) {
+            (_, Closed, _) => return None,
+            // The peer may give up at any point
+            (_, _, "error") => Closed,
+            (_, KeyExchange, "pake") => IpExchange,
+            (true, IpExchange, "externalip") => FileTransfare,
+            (false, IpExchange, "externalip") => FileInfoTransfare,
+            (false, FileInfoTransfare, "fileinfo") => FileTransfared,
+            (true, FileTransfare, "recipientready") => FileTransfare,
+            // Every received file is closed before the next one is asked for
+            (true, FileTransfare, "close-sender") => FileTransfare,
+            (false, FileTransfared, "close-recipient") => FileTransfared,
+            (true, FileTransfare, "finished") | (false, FileTransfared, "finished") => Closed,
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::ClientState;
+
+    const KINDS: [&str; 8] = [
+        "pake",
+        "externalip",
+        "fileinfo",
+        "recipientready",
+        "close-sender",
+        "close-recipient",
+        "error",
+        "finished",
+    ];
+
+    fn kinds() -> impl Strategy<Value = Vec<&'static str>> {
+        prop::collection::vec(prop::sample::select(KINDS.to_vec()), 0..12)
+    }
+
+    /// Feeds `kinds` in order and returns the ones accepted before the first
+    /// refusal.
+    fn accepted(is_sender: bool, kinds: &[&'static str]) -> Vec<&'static str> {
+        let mut state = ClientState::default();
+        let mut accepted = vec![];
+        for kind in kinds {
+            match state.next(is_sender, kind) {
+                Some(next) => state = next,
+                None => break,
+            }
+            accepted.push(*kind);
+        }
+        accepted
+    }
+
+    /// Whether `kinds` is the start of a transfer, possibly cut short by an
+    /// error from the peer.
+    fn follows_protocol(is_sender: bool, kinds: &[&str]) -> bool {
+        let kinds = match kinds.split_last() {
+            Some((&"error", rest)) if rest.last() != Some(&"finished") => rest,
+            _ => kinds,
+        };
+        let transcript: Vec<&str> = if is_sender {
+            let requests = kinds
+                .iter()
+                .skip(2)
+                .take_while(|kind| ["recipientready", "close-sender"].contains(kind))
+                .copied();
+            ["pake", "externalip"]
+                .iter()
+                .copied()
+                .chain(requests)
+                .chain(std::iter::once("finished"))
+                .collect()
+        } else {
+            let closes = kinds
+                .iter()
+                .skip(3)
+                .take_while(|kind| **kind == "close-recipient")
+                .copied();
+            ["pake", "externalip", "fileinfo"]
+                .iter()
+                .copied()
+                .chain(closes)
+                .chain(std::iter::once("finished"))
+                .collect()
+        };
+        transcript.starts_with(kinds)
+    }
+
+    #[test]
+    fn test_full_transfers() {
+        let sender = [
+            "pake",
+            "externalip",
+            "recipientready",
+            "close-sender",
+            "recipientready",
+            "close-sender",
+            "finished",
+        ];
+        assert_eq!(accepted(true, &sender), sender);
+        let receiver = [
+            "pake",
+            "externalip",
+            "fileinfo",
+            "close-recipient",
+            "close-recipient",
+            "finished",
+        ];
+        assert_eq!(accepted(false, &receiver), receiver);
+    }
+
+    proptest! {
+        #[test]
+        fn test_only_the_protocol_is_accepted(is_sender in any::<bool>(), kinds in kinds()) {
+            let accepted = accepted(is_sender, &kinds);
+            prop_assert!(follows_protocol(is_sender, &accepted), "{:?}", accepted);
+            // Whatever came next would have broken the protocol
+            if let Some(refused) = kinds.get(accepted.len()) {
+                let mut longer = accepted.clone();
+                longer.push(*refused);
+                prop_assert!(!follows_protocol(is_sender, &longer), "{:?}", longer);
+            }
+        }
+
+        #[test]
+        fn test_no_files_before_keys(is_sender in any::<bool>(), kinds in kinds()) {
+            let accepted = accepted(is_sender, &kinds);
+            for kind in ["fileinfo", "recipientready"] {
+                if let Some(files) = accepted.iter().position(|k| *k == kind) {
+                    prop_assert!(accepted[..files].contains(&"pake"));
+                }
+            }
+        }
+    }
+}
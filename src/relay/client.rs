@@ -1,78 +1,197 @@
 use crate::proto::client_session::ClientSession;
 use crate::proto::{
-    AsyncCrocRead, AsyncCrocWrite, CrocProto, EncryptedSession, MpscCrocProto,
+    AsyncCrocRead, AsyncCrocWrite, CrocProto, EncryptedSession, MpscCrocProto, ERROR_PREFIX,
 };
+use crate::relay::server;
 use anyhow::{Context, Result};
 use rust_pake::pake::Role;
+use sha2::{Digest, Sha256};
 
-use tokio::net::ToSocketAddrs;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+// How long a receiver waits for a sender's local relay to answer
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
 
 #[derive(thiserror::Error, Debug)]
-enum RelayClientError {
+pub enum RelayClientError {
+    #[error("The relay rejected the password")]
+    BadPassword,
     #[error("Something went wrong with received response {0}")]
     BadResponse(String),
-    #[error("Symmetric Key negotiation failed")]
-    KeyNegotiationFailiure,
+    #[error("The relay refused the room: {0}")]
+    Refused(String),
     #[error("The room requested ({0}) is full")]
     RoomFull(String),
     #[error("Room negotiation failed for unknown reason")]
     RoomNegotiationFailed,
+    #[error("The relay dropped the room after waiting too long for the other side")]
+    RoomExpired,
     #[error("Got unknown bytes from relay while keepaliving {0:?}")]
     UnknownKeepaliveMessage(Vec<u8>),
     #[error("Shared secret used in client is invalid {0}")]
     BadSharedSecret(String),
 }
-struct Config {
-    relay_ports: Vec<String>,
-    relay_address: String,
-    relay_password: String,
-    room: String,
-    disable_local: bool,
+/// Where the data connections of a session go: the relay the client joined
+/// and the ports its banner listed.
+#[derive(Debug, Clone)]
+pub struct RelayInfo {
+    pub host: IpAddr,
+    pub password: String,
+    pub ports: Vec<String>,
+}
+
+impl RelayInfo {
+    pub fn address(&self, port: &str) -> Result<SocketAddr> {
+        Ok(SocketAddr::new(self.host, port.parse()?))
+    }
 }
 
 pub struct RelayClient {
     stream: CrocProto,
-    relay_ports: Vec<String>,
+    relay: RelayInfo,
+    // Main port of the relay the sender runs on its own network
+    local_relay_port: Option<u16>,
     external_ip: Option<String>,
     disable_local: bool,
+    is_sender: bool,
     shared_secret: String,
 }
+/// Checks that a relay is listening at `address` with the raw ping Go croc uses.
+pub async fn ping(address: SocketAddr) -> Result<()> {
+    let mut stream = TcpStream::connect(address).await?;
+    stream.write_all(b"ping").await?;
+    let mut pong = [0u8; 4];
+    stream.read_exact(&mut pong).await?;
+    if &pong != b"pong" {
+        return Err(RelayClientError::BadResponse(format!("{pong:?}")).into());
+    }
+    Ok(())
+}
+
+/// Waits for the receiver on both the public relay and the sender's local one,
+/// the session continues on whichever the receiver joins.
+pub async fn wait_for_receiver_on_either(
+    public: RelayClient,
+    local: RelayClient,
+) -> Result<ClientSession> {
+    let public = public.wait_for_receiver();
+    let local = local.wait_for_receiver();
+    tokio::pin!(public, local);
+    tokio::select! {
+        session = &mut public => match session {
+            Ok(session) => Ok(session),
+            Err(err) => {
+                warn!("Lost the public relay, waiting on the local one: {err}");
+                local.await.map(ClientSession::on_local_relay)
+            }
+        },
+        session = &mut local => match session {
+            Ok(session) => Ok(session.on_local_relay()),
+            Err(err) => {
+                warn!("Lost the local relay, waiting on the public one: {err}");
+                public.await
+            }
+        },
+    }
+}
+
+/// Splits the secret into the part picking the room and the part used for the
+/// PAKE, what is left after the "NNNN-" prefix.
+pub fn split_secret(shared_secret: &str) -> Result<(&str, &str), RelayClientError> {
+    match (shared_secret.get(..4), shared_secret.get(5..)) {
+        (Some(room), Some(pake)) if !pake.is_empty() => Ok((room, pake)),
+        _ => Err(RelayClientError::BadSharedSecret(shared_secret.to_string())),
+    }
+}
+
+/// Go croc keeps the code away from the relay by naming the room after a hash
+/// of its first four characters.
+pub fn room_name(shared_secret: &str) -> Result<String, RelayClientError> {
+    let (room, _) = split_secret(shared_secret)?;
+    Ok(hex::encode(Sha256::digest(format!("{room}croc"))))
+}
+
 impl RelayClient {
     pub async fn connect<A: ToSocketAddrs>(
         relay_addr: A,
         password: &str,
         shared_secret: &str,
         disable_local: bool,
+        is_sender: bool,
     ) -> Result<Self> {
-        if shared_secret.len() < 4 {
-            return Err(RelayClientError::BadSharedSecret(shared_secret.to_string()).into());
-        }
+        let mut transferer =
+            Self::connect_to_room(relay_addr, password, &room_name(shared_secret)?).await?;
+        transferer.shared_secret = shared_secret.to_string();
+        transferer.disable_local = disable_local;
+        transferer.is_sender = is_sender;
+        Ok(transferer)
+    }
+    /// Joins `room` as is, used for the data connections whose rooms are
+    /// derived from the main one.
+    pub async fn connect_to_room<A: ToSocketAddrs>(
+        relay_addr: A,
+        password: &str,
+        room: &str,
+    ) -> Result<Self> {
+        let stream = CrocProto::connect(relay_addr).await?;
+        let relay = RelayInfo {
+            host: stream.connection.peer_addr()?.ip(),
+            password: password.to_string(),
+            ports: vec![],
+        };
         let mut transferer = RelayClient {
-            stream: CrocProto::connect(relay_addr).await?,
-            relay_ports: vec![],
-            disable_local,
-            shared_secret: shared_secret.to_string(),
+            stream,
+            relay,
+            local_relay_port: None,
+            disable_local: true,
+            is_sender: false,
+            shared_secret: "".to_string(),
             external_ip: None,
         };
         let sym_key = &transferer
             .stream
             .negotiate_symmetric_key(rust_pake::pake::Role::Sender)
             .await?;
-        transferer
-            .negotiate_info(sym_key, password, &shared_secret[..3])
-            .await?;
+        transferer.negotiate_info(sym_key, password, room).await?;
         Ok(transferer)
     }
     pub fn start_mpsc_stream(self) -> Result<MpscCrocProto> {
         MpscCrocProto::from_stream(self.stream.connection)
     }
+    /// Tells receivers asking for `ips?` that the sender also waits on its
+    /// own relay listening on `port`.
+    pub fn with_local_relay(mut self, port: u16) -> Self {
+        self.local_relay_port = Some(port);
+        self
+    }
     pub async fn connect_to_sender(mut self) -> Result<ClientSession> {
+        if !self.disable_local {
+            if let Some(local_relay) = self.find_local_relay().await? {
+                info!("Sender is on the local network, using its relay at {local_relay}");
+                // The public relay connection is dropped with the old client
+                self = RelayClient::connect(
+                    local_relay,
+                    &self.relay.password,
+                    &self.shared_secret,
+                    true,
+                    false,
+                )
+                .await?;
+            }
+        }
         debug!("Sending handshake");
         // Keep the connection untill a transfer request has
         self.stream.write(b"handshake").await?;
         Ok(ClientSession::new(
             self.stream,
-            self.relay_ports,
+            self.relay,
             self.shared_secret,
             false,
             self.external_ip.context("Did not receive external IP")?,
@@ -84,7 +203,7 @@ impl RelayClient {
         self.handle_keepalive().await?;
         Ok(ClientSession::new(
             self.stream,
-            self.relay_ports,
+            self.relay,
             self.shared_secret,
             true,
             self.external_ip.context("Did not receive external IP")?,
@@ -99,10 +218,10 @@ impl RelayClient {
         loop {
             let data = self.stream.read().await?;
             match data.as_slice() {
-                b"ips?" => {
+                b"ips?" if self.is_sender => {
                     let mut ips = vec![];
-                    if !self.disable_local {
-                        ips.push(self.relay_ports[0].clone());
+                    if let Some(local_relay_port) = self.local_relay_port {
+                        ips.push(local_relay_port.to_string());
                         let interfaces = default_net::get_interfaces();
                         for interface in interfaces {
                             for ip in interface.ipv4 {
@@ -127,11 +246,50 @@ impl RelayClient {
                     // Ping
                     debug!("Got ping");
                 }
-                _ => return Err(RelayClientError::UnknownKeepaliveMessage(data))?,
+                _ => {
+                    return match data.strip_prefix(ERROR_PREFIX) {
+                        Some(reason) if reason == server::ROOM_EXPIRED.as_bytes() => {
+                            Err(RelayClientError::RoomExpired)?
+                        }
+                        Some(reason) => Err(RelayClientError::Refused(
+                            String::from_utf8_lossy(reason).into(),
+                        ))?,
+                        None => Err(RelayClientError::UnknownKeepaliveMessage(data))?,
+                    }
+                }
             }
         }
     }
 
+    /// Asks the sender for the addresses of its local relay and returns the
+    /// first one answering a ping.
+    async fn find_local_relay(&mut self) -> Result<Option<SocketAddr>> {
+        self.stream.write(b"ips?").await?;
+        let reply = loop {
+            let data = self.stream.read().await?;
+            if data != [1u8] {
+                break data;
+            }
+        };
+        let ips: Vec<String> = serde_json::from_slice(&reply)
+            .map_err(|_| RelayClientError::BadResponse(format!("{reply:?}")))?;
+        debug!("Sender ips: {ips:?}");
+        let Some((port, ips)) = ips.split_first() else {
+            return Ok(None);
+        };
+        for ip in ips {
+            let Ok(address) = format!("{ip}:{port}").parse::<SocketAddr>() else {
+                continue;
+            };
+            match tokio::time::timeout(PING_TIMEOUT, ping(address)).await {
+                Ok(Ok(())) => return Ok(Some(address)),
+                Ok(Err(err)) => debug!("Could not ping {address}: {err}"),
+                Err(_) => debug!("Timed out pinging {address}"),
+            }
+        }
+        Ok(None)
+    }
+
     async fn negotiate_info(
         &mut self,
         sym_key: &[u8; 32],
@@ -145,6 +303,9 @@ impl RelayClient {
 
         // Banner/IpAddress
         let message = String::from_utf8(enc.read(&mut self.stream).await?)?;
+        if message == "bad password" {
+            return Err(RelayClientError::BadPassword)?;
+        }
         if !message.contains("|||") {
             return Err(RelayClientError::BadResponse(message.to_string()))?;
         }
@@ -154,7 +315,7 @@ impl RelayClient {
         self.external_ip = Some(ipaddr.to_string());
         debug!("Benner: {banner}");
         debug!("Ipaddr: {ipaddr}");
-        self.relay_ports = banner.split(",").map(|banner| banner.to_string()).collect();
+        self.relay.ports = banner.split(",").map(|banner| banner.to_string()).collect();
 
         debug!("Negotiating room: {room}");
         // Send room number
@@ -164,6 +325,10 @@ impl RelayClient {
         if response != b"ok" {
             return if response == b"room full" {
                 Err(RelayClientError::RoomFull(room.to_string()))?
+            } else if let Some(reason) = response.strip_prefix(ERROR_PREFIX) {
+                Err(RelayClientError::Refused(
+                    String::from_utf8_lossy(reason).into(),
+                ))?
             } else {
                 Err(RelayClientError::RoomNegotiationFailed)?
             };
@@ -1,56 +1,150 @@
-use std::sync::Arc;
+use std::{convert::TryFrom, future::Future, path::Path, sync::Arc};
 
-use anyhow::Result;
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
-    sync::Mutex,
+use anyhow::{anyhow, Context, Result};
+use tokio::{fs::File, io::AsyncReadExt, task::JoinSet};
+
+use crate::{
+    common::{compress::CompressionAlgorithm, pio},
+    crypto::aes::AesEncryptor,
+    proto::{EncryptedSession, OwnedSender},
 };
+// How many chunks are compressed and encrypted, or decrypted and written, at once
+const WORKERS: usize = 8;
 
-use crate::proto::{EncryptedSession, OwnedSender};
 pub struct FileChunkInfo {
-    pub file: Arc<Mutex<File>>,
+    // Chunks are read at their offset, so the file needs no lock
+    pub file: Arc<std::fs::File>,
     pub chunk_size: usize,
     pub chunk_offset: usize,
+    pub compression: Option<CompressionAlgorithm>,
+    // Gets the amount of bytes sent once the chunk is on its data connection
+    pub sent: tokio::sync::mpsc::Sender<Result<usize>>,
+}
+
+/// Starts `job` once fewer than `WORKERS` jobs are running. Jobs report their
+/// own results, a panicking one is only logged.
+async fn spawn_bounded<F>(workers: &mut JoinSet<()>, job: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    while workers.len() >= WORKERS {
+        if let Some(Err(err)) = workers.join_next().await {
+            error!("Chunk worker failed: {err}");
+        }
+    }
+    workers.spawn(job);
+}
+
+async fn read_chunk_at(file_chunk_info: &FileChunkInfo) -> Result<Vec<u8>> {
+    let file = file_chunk_info.file.clone();
+    let mut chunk = vec![0u8; file_chunk_info.chunk_size];
+    let offset = file_chunk_info.chunk_offset as u64;
+    tokio::task::spawn_blocking(move || {
+        pio::read_exact_at(&file, &mut chunk, offset)?;
+        Ok(chunk)
+    })
+    .await?
+}
+
+async fn send_chunk(
+    mut chunk: Vec<u8>,
+    offset: usize,
+    compression: Option<CompressionAlgorithm>,
+    mut sender: OwnedSender,
+    encrypted_session: EncryptedSession,
+) -> Result<usize> {
+    let size = chunk.len();
+    // Construct a buffer that has the position and then the data:
+    let mut buffer = offset.to_le_bytes().to_vec();
+    buffer.append(&mut chunk);
+    // Like Go croc, the offset is compressed along with the data
+    if let Some(compression) = compression {
+        buffer = compression.compress(&buffer)?;
+    }
+    encrypted_session.write(&mut sender, &buffer).await?;
+    Ok(size)
 }
+
 async fn fs_reader_task(
     mut fs_receiver: tokio::sync::mpsc::Receiver<FileChunkInfo>,
-    sender_tx: OwnedSender,
+    senders_tx: Vec<OwnedSender>,
     encrypted_session: EncryptedSession,
 ) -> Result<()> {
     debug!("fs_reader_task started");
-    let encrypted_session_ref = &encrypted_session.clone();
-    // Receive a file structure to transmit and sends the actual data to the sender task:
+    // Chunks are striped over the data connections, one after the other
+    let mut connections = senders_tx.iter().cycle();
+    let mut workers = JoinSet::new();
+    // Reads the chunks in order, compressing, encrypting and sending happens in the workers
     while let Some(file_chunk_info) = fs_receiver.recv().await {
-        let file = file_chunk_info.file;
-        let mut chunk = vec![0u8; file_chunk_info.chunk_size];
-        {
-            let mut file = file.lock().await;
-            file.seek(std::io::SeekFrom::Start(
-                file_chunk_info.chunk_offset as u64,
-            ))
-            .await?;
-            file.read_exact(&mut chunk).await?;
-        }
-        // create an encrypt task that will encrypt the chunk and send it to the sender task:
-        // spawn task:
-
-        // Construct a buffer that has the position and then the data:
-        let mut buffer = file_chunk_info.chunk_offset.to_le_bytes().to_vec();
-        buffer.append(&mut chunk);
-        let mut sender = sender_tx.clone();
-        let encr = encrypted_session_ref.clone();
-
-        // TODO move to encryptor task using IPC
-        // encr.
-        tokio::task::spawn(async move { encr.write(&mut sender, &buffer).await });
+        let chunk = match read_chunk_at(&file_chunk_info).await {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                let _ = file_chunk_info.sent.send(Err(err)).await;
+                continue;
+            }
+        };
+        let sender = connections
+            .next()
+            .ok_or(anyhow!("No data connection to send chunks on"))?
+            .clone();
+        let encrypted_session = encrypted_session.clone();
+        spawn_bounded(&mut workers, async move {
+            let result = send_chunk(
+                chunk,
+                file_chunk_info.chunk_offset,
+                file_chunk_info.compression,
+                sender,
+                encrypted_session,
+            )
+            .await;
+            // The sender may have given up on this file already
+            let _ = file_chunk_info.sent.send(result).await;
+        })
+        .await;
     }
+    while workers.join_next().await.is_some() {}
     debug!("fs_reader_task ended");
     Ok(())
 }
 pub struct FileChunk {
-    pub file: Arc<Mutex<File>>,
+    // Chunks are written at their offset, so the file needs no lock
+    pub file: Arc<std::fs::File>,
     pub data: Vec<u8>,
+    pub compression: Option<CompressionAlgorithm>,
+    // Gets the amount of bytes written once the chunk is on disk
+    pub written: tokio::sync::mpsc::Sender<Result<usize>>,
+}
+/// Decrypts and decompresses a chunk received on a data connection, returns
+/// its offset in the file and its data.
+pub fn decode_chunk(
+    data: &[u8],
+    compression: Option<CompressionAlgorithm>,
+    encryptor: &AesEncryptor,
+) -> Result<(u64, Vec<u8>)> {
+    let data = encryptor.decrypt(data).context("Could not decrypt chunk")?;
+    let mut data = match compression {
+        Some(compression) => compression
+            .decompress(&data)
+            .context("Could not decompress chunk")?,
+        None => data,
+    };
+    if data.len() < 8 {
+        return Err(anyhow!("Chunk is too short to hold its offset"));
+    }
+    // the opposite of fs_reader_task, get offset value from data
+    let mut offset_bytes = [0u8; 8];
+    offset_bytes.copy_from_slice(&data[0..8]);
+    data.drain(..8);
+    Ok((u64::from_le_bytes(offset_bytes), data))
+}
+async fn write_chunk(file_chunk: &FileChunk, encryptor: &AesEncryptor) -> Result<usize> {
+    let (offset, data) = decode_chunk(&file_chunk.data, file_chunk.compression, encryptor)?;
+    let file = file_chunk.file.clone();
+    tokio::task::spawn_blocking(move || {
+        pio::write_all_at(&file, &data, offset)?;
+        Ok(data.len())
+    })
+    .await?
 }
 async fn fs_writer_task(
     mut fs_receiver: tokio::sync::mpsc::Receiver<FileChunk>,
@@ -58,44 +152,118 @@ async fn fs_writer_task(
     encrypted_session: EncryptedSession,
 ) -> Result<()> {
     debug!("fs_writer_task started");
-
+    let mut workers = JoinSet::new();
     // Receive data from the receiver task and write it to the file system directly:
     while let Some(file_chunk) = fs_receiver.recv().await {
         let encryptor = encrypted_session.as_encryptor().clone();
-        tokio::spawn(async move {
-            let data = encryptor.decrypt(&file_chunk.data);
-            match data {
-                Ok(data) => {
-                    // the opposite of fs_reader_task
-                    let file = file_chunk.file;
-                    // get offset value from data
-                    let mut offset_bytes = [0u8; 8];
-
-                    offset_bytes.copy_from_slice(&data[0..8]);
-                    let offset = usize::from_le_bytes(offset_bytes);
-                    {
-                        let mut file = file.lock().await;
-                        file.seek(std::io::SeekFrom::Start(offset as u64))
-                            .await
-                            .unwrap();
-                        file.write_all(&data[8..]).await.unwrap();
-                    }
-                }
-                Err(err) => error!("Could not decrypt chunk {err}"),
+        spawn_bounded(&mut workers, async move {
+            let result = write_chunk(&file_chunk, &encryptor).await;
+            if let Err(err) = &result {
+                error!("Could not write chunk {err}");
             }
-        });
+            // The receiver may have given up on this file already
+            let _ = file_chunk.written.send(result).await;
+        })
+        .await;
     }
+    while workers.join_next().await.is_some() {}
     debug!("fs_writer_task ended");
     Ok(())
 }
 
+/// Finds the chunks of a partially received file that were never written,
+/// a chunk still filled with zeros (`set_len` pads with them) counts as missing.
+///
+/// The result is in the Go croc `CurrentFileChunkRanges` format:
+/// `[chunk_size, offset, count, offset, count, ...]`. Like in Go croc, an empty
+/// list means that the whole file should be sent.
+pub async fn missing_chunks(path: &Path, chunk_size: usize) -> Result<Vec<i64>> {
+    let mut file = File::open(path).await?;
+    let mut missing = vec![];
+    let mut buffer = vec![0u8; chunk_size];
+    let mut offset = 0;
+    loop {
+        let amount = read_chunk(&mut file, &mut buffer).await?;
+        if amount == 0 {
+            break;
+        }
+        if buffer[..amount].iter().all(|byte| *byte == 0) {
+            missing.push(offset as i64);
+        }
+        offset += amount;
+    }
+    if missing.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut ranges = vec![chunk_size as i64];
+    for offset in missing {
+        match ranges.len() {
+            len if len > 1 && ranges[len - 2] + ranges[len - 1] * chunk_size as i64 == offset => {
+                ranges[len - 1] += 1
+            }
+            _ => ranges.extend([offset, 1]),
+        }
+    }
+    Ok(ranges)
+}
+
+/// Reads until `buffer` is full or the file ends.
+async fn read_chunk(file: &mut File, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let amount = file.read(&mut buffer[filled..]).await?;
+        if amount == 0 {
+            break;
+        }
+        filled += amount;
+    }
+    Ok(filled)
+}
+
+/// Expands chunk ranges (see `missing_chunks`) to the offsets that have to be sent.
+/// The ranges come from the peer, ones that could not describe `file_size`
+/// bytes are refused.
+pub fn chunk_ranges_to_offsets(
+    ranges: &[i64],
+    file_size: u64,
+    chunk_size: u64,
+) -> Result<Vec<u64>> {
+    let (range_chunk_size, ranges) = match ranges.split_first() {
+        Some((&range_chunk_size, ranges)) if range_chunk_size != 0 => (range_chunk_size, ranges),
+        _ => return Ok((0..file_size).step_by(chunk_size as usize).collect()),
+    };
+    let bad_range = || anyhow!("Bad chunk ranges {ranges:?} of {range_chunk_size} bytes");
+    let range_chunk_size = u64::try_from(range_chunk_size).map_err(|_| bad_range())?;
+    // A whole file is never more chunks than this
+    let max_count = file_size / range_chunk_size + 1;
+    let mut offsets = vec![];
+    for range in ranges.chunks_exact(2) {
+        let (Ok(start), Ok(count)) = (u64::try_from(range[0]), u64::try_from(range[1])) else {
+            return Err(bad_range());
+        };
+        if start >= file_size || count > max_count {
+            return Err(bad_range());
+        }
+        for index in 0..count {
+            let offset = index
+                .checked_mul(range_chunk_size)
+                .and_then(|offset| offset.checked_add(start))
+                .ok_or_else(bad_range)?;
+            if offset < file_size {
+                offsets.push(offset);
+            }
+        }
+    }
+    Ok(offsets)
+}
+
 pub struct CrocFsInterface {
     fs_read_message_sender: tokio::sync::mpsc::Sender<FileChunkInfo>,
     fs_write_message_sender: tokio::sync::mpsc::Sender<FileChunk>,
 }
 impl CrocFsInterface {
     pub async fn new(
-        sender_tx: OwnedSender,
+        senders_tx: Vec<OwnedSender>,
         encrypted_session: EncryptedSession,
     ) -> Result<CrocFsInterface> {
         // initialize fs_receiver
@@ -104,7 +272,7 @@ impl CrocFsInterface {
         // start fs reader task:
         let cloned_encrypted_session = encrypted_session.clone();
         tokio::spawn(async move {
-            fs_reader_task(fs_read_message_receiver, sender_tx, encrypted_session).await
+            fs_reader_task(fs_read_message_receiver, senders_tx, encrypted_session).await
         });
         tokio::spawn(async move {
             fs_writer_task(fs_write_message_receiver, cloned_encrypted_session).await
@@ -124,6 +292,142 @@ impl CrocFsInterface {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{
+        chunk_ranges_to_offsets, missing_chunks, CrocFsInterface, FileChunk, FileChunkInfo,
+    };
+    use crate::{
+        common::compress::CompressionAlgorithm,
+        crypto::aes::AesEncryptor,
+        proto::{EncryptedSession, OwnedSender},
+    };
+
+    #[tokio::test]
+    async fn test_chunk_pipeline() {
+        let session = EncryptedSession::from_encryptor(AesEncryptor::new(&[7u8; 32], None));
+        let content: Vec<u8> = (0..40 * 1000).map(|i| (i % 253) as u8).collect();
+        let source = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(source.path(), &content).unwrap();
+        let destination_file = tempfile::NamedTempFile::new().unwrap();
+
+        let (connection, mut data) = tokio::sync::mpsc::channel(100);
+        let (reader, _) =
+            CrocFsInterface::new(vec![OwnedSender { sender: connection }], session.clone())
+                .await
+                .unwrap()
+                .into_split();
+        let (_, writer) = CrocFsInterface::new(vec![], session)
+            .await
+            .unwrap()
+            .into_split();
+
+        let source = Arc::new(std::fs::File::open(source.path()).unwrap());
+        let (sent, mut sent_receiver) = tokio::sync::mpsc::channel(100);
+        // The last chunk starts past the end of the file
+        for chunk_offset in (0..=content.len()).step_by(1000) {
+            reader
+                .send(FileChunkInfo {
+                    file: source.clone(),
+                    chunk_size: 1000,
+                    chunk_offset,
+                    compression: Some(CompressionAlgorithm::Zstd),
+                    sent: sent.clone(),
+                })
+                .await
+                .unwrap();
+        }
+        let mut results = vec![];
+        for _ in 0..=content.len() / 1000 {
+            results.push(sent_receiver.recv().await.unwrap());
+        }
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+        assert!(results.iter().flatten().all(|size| *size == 1000));
+
+        let destination = Arc::new(std::fs::File::create(destination_file.path()).unwrap());
+        let (written, mut written_receiver) = tokio::sync::mpsc::channel(100);
+        for _ in 0..content.len() / 1000 {
+            writer
+                .send(FileChunk {
+                    file: destination.clone(),
+                    // Without the magic and length the data connection frames it with
+                    data: data.recv().await.unwrap()[8..].to_vec(),
+                    compression: Some(CompressionAlgorithm::Zstd),
+                    written: written.clone(),
+                })
+                .await
+                .unwrap();
+        }
+        // Garbage is reported and not written
+        writer
+            .send(FileChunk {
+                file: destination.clone(),
+                data: b"not a chunk".to_vec(),
+                compression: None,
+                written: written.clone(),
+            })
+            .await
+            .unwrap();
+        let mut failures = 0;
+        for _ in 0..=content.len() / 1000 {
+            failures += written_receiver.recv().await.unwrap().is_err() as usize;
+        }
+        assert_eq!(failures, 1);
+        assert_eq!(std::fs::read(destination_file.path()).unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn test_missing_chunks() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        // chunks: written, missing, missing, written, missing (partial)
+        let mut content = vec![0u8; 4 * 4 + 2];
+        content[..4].copy_from_slice(b"abcd");
+        content[12..16].copy_from_slice(b"efgh");
+        std::fs::write(file.path(), &content).unwrap();
+
+        let ranges = missing_chunks(file.path(), 4).await.unwrap();
+        assert_eq!(ranges, vec![4, 4, 2, 16, 1]);
+        assert_eq!(
+            chunk_ranges_to_offsets(&ranges, content.len() as u64, 4).unwrap(),
+            vec![4, 8, 16]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_no_missing_chunks_means_everything() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), b"complete").unwrap();
+
+        let ranges = missing_chunks(file.path(), 4).await.unwrap();
+        assert!(ranges.is_empty());
+        assert_eq!(
+            chunk_ranges_to_offsets(&ranges, 10, 4).unwrap(),
+            vec![0, 4, 8]
+        );
+    }
+
+    #[test]
+    fn test_bad_chunk_ranges() {
+        for ranges in [
+            vec![-4, 0, 1],
+            vec![4, -8, 1],
+            vec![4, 0, -1],
+            // More chunks than the file has
+            vec![4, 0, 4],
+            vec![4, i64::MAX, 2],
+            vec![i64::MAX, 0, 2],
+        ] {
+            assert!(
+                chunk_ranges_to_offsets(&ranges, 10, 4).is_err(),
+                "{:?}",
+                ranges
+            );
+        }
+    }
+}
+
 // async fn fs_writer_task(mut receiver_rx: tokio::sync::mpsc::Receiver<Vec<u8>>) -> Result<()> {
 //     // Receive data from the receiver task and write it to the file system directly:
 //     while let Some(msg) = receiver_rx.recv().await {
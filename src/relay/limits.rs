@@ -0,0 +1,322 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// Buckets are only dropped once there are more than this many
+const MAX_TRACKED_IPS: usize = 1024;
+
+/// What a relay accepts before it starts refusing clients. `None` is unlimited.
+#[derive(Debug, Clone, Default)]
+pub struct RelayLimits {
+    /// Connections handled at once, waiting senders included.
+    pub max_connections: Option<usize>,
+    /// Rooms open at once.
+    pub max_rooms: Option<usize>,
+    /// Rooms opened by one IP at once, every data connection of a transfer has its own room.
+    pub max_rooms_per_ip: Option<usize>,
+    /// New key exchanges one IP may start every second.
+    pub handshakes_per_second: Option<u32>,
+    /// Bytes relayed every second by the whole relay.
+    pub bytes_per_second: Option<u64>,
+    /// Bytes relayed every second in one room.
+    pub room_bytes_per_second: Option<u64>,
+    /// Bytes relayed every second to and from one IP.
+    pub ip_bytes_per_second: Option<u64>,
+    /// Bytes relayed in one room before it is closed.
+    pub max_room_bytes: Option<u64>,
+}
+
+/// Why a client was refused, sent to it as is.
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum LimitError {
+    #[error("too many connections to the relay")]
+    Connections,
+    #[error("too many handshakes from {0}, slow down")]
+    Handshakes(IpAddr),
+    #[error("too many rooms on the relay")]
+    Rooms,
+    #[error("too many rooms from {0}")]
+    RoomsForIp(IpAddr),
+    #[error("room went over its quota of {0} bytes")]
+    RoomBytes(u64),
+}
+
+/// A token bucket holding at most a second worth of tokens.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(rate: f64) -> Bucket {
+        Bucket {
+            tokens: rate,
+            updated: Instant::now(),
+        }
+    }
+    fn refill(&mut self, rate: f64) {
+        let now = Instant::now();
+        self.tokens =
+            (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate).min(rate);
+        self.updated = now;
+    }
+}
+
+/// Bytes per second shared by whoever holds it.
+struct ByteRate {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl ByteRate {
+    fn new(rate: u64) -> ByteRate {
+        ByteRate {
+            rate: rate as f64,
+            bucket: Mutex::new(Bucket::full(rate as f64)),
+        }
+    }
+    /// Takes `amount` tokens, going into debt if needed, and returns how long
+    /// to wait until the debt is paid.
+    fn reserve(&self, amount: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill(self.rate);
+        bucket.tokens -= amount as f64;
+        Duration::from_secs_f64((-bucket.tokens).max(0.0) / self.rate)
+    }
+}
+
+/// Slows down and counts the bytes relayed in one room.
+pub struct Throttle {
+    rates: Vec<Arc<ByteRate>>,
+    max_bytes: Option<u64>,
+    relayed: AtomicU64,
+}
+
+impl Throttle {
+    /// Waits until `amount` more bytes may be relayed, fails once the room
+    /// went over its quota.
+    pub async fn take(&self, amount: usize) -> Result<(), LimitError> {
+        let relayed = self.relayed.fetch_add(amount as u64, Ordering::Relaxed) + amount as u64;
+        if let Some(max_bytes) = self.max_bytes.filter(|max_bytes| relayed > *max_bytes) {
+            return Err(LimitError::RoomBytes(max_bytes));
+        }
+        let wait = self
+            .rates
+            .iter()
+            .map(|rate| rate.reserve(amount))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps count of what the clients of a relay use, shared by all its listeners.
+pub struct Limiter {
+    limits: RelayLimits,
+    connections: Option<Arc<Semaphore>>,
+    handshakes: Mutex<HashMap<IpAddr, Bucket>>,
+    rooms_per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    bandwidth: Option<Arc<ByteRate>>,
+    ip_bandwidth: Mutex<HashMap<IpAddr, Arc<ByteRate>>>,
+}
+
+impl Limiter {
+    pub fn new(limits: RelayLimits) -> Limiter {
+        Limiter {
+            connections: limits
+                .max_connections
+                .map(|max_connections| Arc::new(Semaphore::new(max_connections))),
+            bandwidth: limits
+                .bytes_per_second
+                .map(|bytes_per_second| Arc::new(ByteRate::new(bytes_per_second))),
+            limits,
+            handshakes: Mutex::new(HashMap::new()),
+            rooms_per_ip: Arc::new(Mutex::new(HashMap::new())),
+            ip_bandwidth: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a connection slot, given back when the permit is dropped.
+    pub fn connection(&self) -> Result<Option<OwnedSemaphorePermit>, LimitError> {
+        match &self.connections {
+            Some(connections) => connections
+                .clone()
+                .try_acquire_owned()
+                .map(Some)
+                .map_err(|_| LimitError::Connections),
+            None => Ok(None),
+        }
+    }
+
+    /// Takes a token from the bucket of `ip`, which holds a second worth of them.
+    pub fn handshake(&self, ip: IpAddr) -> Result<(), LimitError> {
+        let Some(rate) = self.limits.handshakes_per_second else {
+            return Ok(());
+        };
+        let rate = rate as f64;
+        let mut handshakes = self.handshakes.lock().unwrap();
+        if handshakes.len() > MAX_TRACKED_IPS {
+            // A full bucket is the same as no bucket
+            handshakes.retain(|_, bucket| {
+                bucket.refill(rate);
+                bucket.tokens < rate
+            });
+        }
+        let bucket = handshakes.entry(ip).or_insert(Bucket::full(rate));
+        bucket.refill(rate);
+        if bucket.tokens < 1.0 {
+            return Err(LimitError::Handshakes(ip));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Counts a new room for `ip` while `open_rooms` are open, given back when
+    /// the permit is dropped.
+    pub fn room(&self, ip: IpAddr, open_rooms: usize) -> Result<RoomPermit, LimitError> {
+        if self
+            .limits
+            .max_rooms
+            .is_some_and(|max_rooms| open_rooms >= max_rooms)
+        {
+            return Err(LimitError::Rooms);
+        }
+        let mut rooms_per_ip = self.rooms_per_ip.lock().unwrap();
+        let rooms = rooms_per_ip.entry(ip).or_default();
+        if self
+            .limits
+            .max_rooms_per_ip
+            .is_some_and(|max_rooms_per_ip| *rooms >= max_rooms_per_ip)
+        {
+            return Err(LimitError::RoomsForIp(ip));
+        }
+        *rooms += 1;
+        Ok(RoomPermit {
+            ip,
+            rooms_per_ip: self.rooms_per_ip.clone(),
+        })
+    }
+
+    /// Makes the throttle of a room relaying between `ips`.
+    pub fn throttle(&self, ips: &[IpAddr]) -> Throttle {
+        let mut rates: Vec<Arc<ByteRate>> = self.bandwidth.iter().cloned().collect();
+        if let Some(rate) = self.limits.room_bytes_per_second {
+            rates.push(Arc::new(ByteRate::new(rate)));
+        }
+        if let Some(rate) = self.limits.ip_bytes_per_second {
+            let mut ip_bandwidth = self.ip_bandwidth.lock().unwrap();
+            // No room relays for these anymore
+            ip_bandwidth.retain(|_, rate| Arc::strong_count(rate) > 1);
+            let mut ips = ips.to_vec();
+            // Both sides may be behind the same IP, it is only slowed down once
+            ips.sort();
+            ips.dedup();
+            for ip in ips {
+                rates.push(
+                    ip_bandwidth
+                        .entry(ip)
+                        .or_insert_with(|| Arc::new(ByteRate::new(rate)))
+                        .clone(),
+                );
+            }
+        }
+        Throttle {
+            rates,
+            max_bytes: self.limits.max_room_bytes,
+            relayed: AtomicU64::new(0),
+        }
+    }
+}
+
+pub struct RoomPermit {
+    ip: IpAddr,
+    rooms_per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for RoomPermit {
+    fn drop(&mut self) {
+        let mut rooms_per_ip = self.rooms_per_ip.lock().unwrap();
+        if let Some(rooms) = rooms_per_ip.get_mut(&self.ip) {
+            *rooms -= 1;
+            if *rooms == 0 {
+                rooms_per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{LimitError, Limiter, RelayLimits};
+
+    #[test]
+    fn test_limiter() {
+        let limiter = Limiter::new(RelayLimits {
+            max_connections: Some(1),
+            max_rooms: Some(3),
+            max_rooms_per_ip: Some(2),
+            handshakes_per_second: Some(2),
+            ..Default::default()
+        });
+        let first: IpAddr = "10.0.0.1".parse().unwrap();
+        let second: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let connection = limiter.connection().unwrap();
+        assert_eq!(limiter.connection().err(), Some(LimitError::Connections));
+        drop(connection);
+        limiter.connection().unwrap();
+
+        limiter.handshake(first).unwrap();
+        limiter.handshake(first).unwrap();
+        assert_eq!(limiter.handshake(first), Err(LimitError::Handshakes(first)));
+        // Every IP has its own bucket
+        limiter.handshake(second).unwrap();
+
+        let room = limiter.room(first, 0).unwrap();
+        let _other_room = limiter.room(first, 1).unwrap();
+        assert_eq!(
+            limiter.room(first, 2).err(),
+            Some(LimitError::RoomsForIp(first))
+        );
+        assert_eq!(limiter.room(second, 3).err(), Some(LimitError::Rooms));
+        drop(room);
+        limiter.room(first, 1).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let limiter = Limiter::new(RelayLimits {
+            ip_bytes_per_second: Some(100_000),
+            max_room_bytes: Some(250_000),
+            ..Default::default()
+        });
+        let throttle = limiter.throttle(&[ip, ip]);
+        let started = std::time::Instant::now();
+        // A second worth of bytes is let through at once, the next second is waited for
+        for _ in 0..200 {
+            throttle.take(1000).await.unwrap();
+        }
+        assert!(started.elapsed() >= std::time::Duration::from_millis(900));
+        // Another room from the same IP shares its rate
+        let started = std::time::Instant::now();
+        limiter.throttle(&[ip]).take(50_000).await.unwrap();
+        assert!(started.elapsed() >= std::time::Duration::from_millis(400));
+
+        throttle.take(50_000).await.unwrap();
+        assert_eq!(throttle.take(1).await, Err(LimitError::RoomBytes(250_000)));
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    fmt::Write as _,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::relay::server::Rooms;
+
+// Upper bounds, in seconds, of the relay duration histogram buckets
+const DURATION_BUCKETS: [f64; 8] = [1.0, 10.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 10800.0];
+// Longer requests are not for us
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Why a client did not get a room, counted by `Metrics`.
+#[derive(thiserror::Error, Debug, Clone, Copy)]
+pub enum HandshakeFailure {
+    #[error("bad magic")]
+    BadMagic,
+    #[error("bad password")]
+    BadPassword,
+    #[error("room full")]
+    RoomFull,
+    #[error("over a relay limit")]
+    OverLimit,
+    #[error("timed out")]
+    TimedOut,
+}
+
+impl HandshakeFailure {
+    const ALL: [HandshakeFailure; 5] = [
+        HandshakeFailure::BadMagic,
+        HandshakeFailure::BadPassword,
+        HandshakeFailure::RoomFull,
+        HandshakeFailure::OverLimit,
+        HandshakeFailure::TimedOut,
+    ];
+    fn label(&self) -> &'static str {
+        match self {
+            HandshakeFailure::BadMagic => "bad_magic",
+            HandshakeFailure::BadPassword => "bad_password",
+            HandshakeFailure::RoomFull => "room_full",
+            HandshakeFailure::OverLimit => "over_limit",
+            HandshakeFailure::TimedOut => "timed_out",
+        }
+    }
+}
+
+/// What the relay counts, shared by all its listeners. The room gauges are
+/// read from the rooms themselves when scraped.
+#[derive(Default)]
+pub struct Metrics {
+    pub sender_to_receiver: AtomicU64,
+    pub receiver_to_sender: AtomicU64,
+    handshake_failures: [AtomicU64; HandshakeFailure::ALL.len()],
+    relay_durations: [AtomicU64; DURATION_BUCKETS.len()],
+    relay_duration_sum_ms: AtomicU64,
+    relay_count: AtomicU64,
+}
+
+impl Metrics {
+    pub fn handshake_failed(&self, failure: HandshakeFailure) {
+        self.handshake_failures[failure as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn relay_ended(&self, duration: Duration) {
+        for (bucket, bound) in self.relay_durations.iter().zip(DURATION_BUCKETS) {
+            if duration.as_secs_f64() <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.relay_duration_sum_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+        self.relay_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Writes the metrics in the Prometheus text format.
+    pub fn render(&self, rooms: usize, waiting_senders: usize) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            let _ = writeln!(text, "# HELP {name} {help}");
+            let _ = writeln!(text, "# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(text, "{name}{labels} {value}");
+            }
+        };
+        let value = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        metric(
+            "croc_relay_rooms",
+            "gauge",
+            "Rooms open on the relay.",
+            &[("".into(), rooms.to_string())],
+        );
+        metric(
+            "croc_relay_waiting_senders",
+            "gauge",
+            "Senders waiting in a room for their receiver.",
+            &[("".into(), waiting_senders.to_string())],
+        );
+        metric(
+            "croc_relay_bytes_total",
+            "counter",
+            "Bytes relayed between senders and receivers.",
+            &[
+                (
+                    "{direction=\"sender_to_receiver\"}".into(),
+                    value(&self.sender_to_receiver),
+                ),
+                (
+                    "{direction=\"receiver_to_sender\"}".into(),
+                    value(&self.receiver_to_sender),
+                ),
+            ],
+        );
+        metric(
+            "croc_relay_handshake_failures_total",
+            "counter",
+            "Clients that did not get a room.",
+            &HandshakeFailure::ALL
+                .iter()
+                .map(|failure| {
+                    (
+                        format!("{{reason=\"{}\"}}", failure.label()),
+                        value(&self.handshake_failures[*failure as usize]),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        );
+        let mut durations: Vec<(String, String)> = DURATION_BUCKETS
+            .iter()
+            .zip(&self.relay_durations)
+            .map(|(bound, bucket)| (format!("_bucket{{le=\"{bound}\"}}"), value(bucket)))
+            .collect();
+        durations.extend([
+            ("_bucket{le=\"+Inf\"}".into(), value(&self.relay_count)),
+            (
+                "_sum".into(),
+                (self.relay_duration_sum_ms.load(Ordering::Relaxed) as f64 / 1000.0).to_string(),
+            ),
+            ("_count".into(), value(&self.relay_count)),
+        ]);
+        metric(
+            "croc_relay_duration_seconds",
+            "histogram",
+            "How long rooms relayed for.",
+            &durations,
+        );
+        text
+    }
+}
+
+async fn scrape(metrics: &Metrics, rooms: &Rooms) -> String {
+    // Not holding the rooms while every room is locked
+    let rooms: Vec<_> = rooms.lock().await.values().cloned().collect();
+    let mut waiting_senders = 0;
+    for room in &rooms {
+        if room.lock().await.is_waiting() {
+            waiting_senders += 1;
+        }
+    }
+    metrics.render(rooms.len(), waiting_senders)
+}
+
+/// Answers `GET /metrics` and nothing else.
+async fn handle_request(mut stream: TcpStream, metrics: &Metrics, rooms: &Rooms) -> Result<()> {
+    let mut request = vec![];
+    let mut buffer = [0u8; 1024];
+    while !request.ends_with(b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let amount = stream.read(&mut buffer).await?;
+        if amount == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..amount]);
+    }
+    let (status, body) = if request.starts_with(b"GET /metrics ") {
+        ("200 OK", scrape(metrics, rooms).await)
+    } else {
+        ("404 Not Found", "".to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Serves the metrics over HTTP on `listener` for Prometheus to scrape.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    metrics: std::sync::Arc<Metrics>,
+    rooms: Rooms,
+) -> Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        debug!("Metrics requested by {addr}");
+        let (metrics, rooms) = (metrics.clone(), rooms.clone());
+        tokio::spawn(async move {
+            match tokio::time::timeout(REQUEST_TIMEOUT, handle_request(stream, &metrics, &rooms))
+                .await
+            {
+                Ok(Err(err)) => debug!("Could not answer {addr}: {err}"),
+                Err(_) => debug!("Timed out answering {addr}"),
+                Ok(Ok(())) => {}
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{HandshakeFailure, Metrics};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.handshake_failed(HandshakeFailure::RoomFull);
+        metrics.handshake_failed(HandshakeFailure::RoomFull);
+        metrics.relay_ended(Duration::from_millis(5500));
+        metrics.relay_ended(Duration::from_secs(120));
+        let text = metrics.render(3, 1);
+        for line in [
+            "# TYPE croc_relay_rooms gauge",
+            "croc_relay_rooms 3",
+            "croc_relay_waiting_senders 1",
+            "croc_relay_bytes_total{direction=\"sender_to_receiver\"} 0",
+            "croc_relay_handshake_failures_total{reason=\"room_full\"} 2",
+            "croc_relay_handshake_failures_total{reason=\"bad_magic\"} 0",
+            "# TYPE croc_relay_duration_seconds histogram",
+            "croc_relay_duration_seconds_bucket{le=\"1\"} 0",
+            "croc_relay_duration_seconds_bucket{le=\"10\"} 1",
+            "croc_relay_duration_seconds_bucket{le=\"300\"} 2",
+            "croc_relay_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "croc_relay_duration_seconds_sum 125.5",
+            "croc_relay_duration_seconds_count 2",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} missing from\n{}",
+                line,
+                text
+            );
+        }
+    }
+}
@@ -1,38 +1,58 @@
 pub mod client;
 pub mod fs;
+pub mod limits;
+pub mod metrics;
 pub mod server;
 
 #[cfg(test)]
 mod tests {
 
     use serial_test::serial;
-    use std::{io::Write, path::PathBuf};
+    use std::{
+        convert::TryInto,
+        io::Write,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    };
     use tempfile::NamedTempFile;
-    use tokio;
+    use tokio::sync::mpsc::UnboundedSender;
 
     use crate::{
-        proto::{AsyncCrocRead, AsyncCrocWrite, FileInfo, FilesInformation},
-        relay::{client, server},
+        common::{
+            compress::{compress, decompress, CompressionAlgorithm},
+            config::{Config, OverwriteMode, ReceivePolicy},
+            interaction::{AutoAccept, Interaction},
+        },
+        crypto::{
+            aes::AesEncryptor,
+            hash::{hash_file, HashAlgorithm},
+        },
+        proto::{
+            client_session::SessionError, AsyncCrocRead, AsyncCrocWrite, CrocProto,
+            EncryptedSession, FileInfo, FilesInformation, MpscCrocProto, ProgressEvent, ProtoError,
+        },
+        relay::{client, limits::RelayLimits, server},
     };
-    use anyhow::Result;
+    use anyhow::{Context, Result};
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use rust_pake::pake::{Pake, Role};
+    use sha2::{Digest, Sha256};
     #[tokio::test]
     #[serial]
     async fn test_relay() {
-        let relay_task = tokio::task::spawn(async {
-            let relay = server::Relay::new(
-                "0.0.0.0:9009".to_string(),
-                "pass123".to_string(),
-                vec![9010],
-            );
-            relay.start().await.unwrap();
-        });
+        let relay_task = spawn_relay(vec![9010]);
 
         async fn client_a() -> Result<()> {
             const MSG: &str = "hello";
             let default_relay_addr = "localhost:9009";
-            let transferer =
-                client::RelayClient::connect(default_relay_addr, "pass123", "12345", false)
-                    .await?;
+            let transferer = client::RelayClient::connect(
+                default_relay_addr,
+                "pass123",
+                "1234-tests",
+                false,
+                true,
+            )
+            .await?;
             let mut client = transferer.wait_for_receiver().await?;
             debug!("Start sending");
             client.stream.write(MSG.as_bytes()).await?;
@@ -41,9 +61,14 @@ mod tests {
         }
         async fn client_b() -> Result<()> {
             let default_relay_addr = "localhost:9009";
-            let transferer2: client::RelayClient =
-                client::RelayClient::connect(default_relay_addr, "pass123", "12345", false)
-                    .await?;
+            let transferer2: client::RelayClient = client::RelayClient::connect(
+                default_relay_addr,
+                "pass123",
+                "1234-tests",
+                false,
+                false,
+            )
+            .await?;
             let mut client2 = transferer2.connect_to_sender().await?;
             let buff = client2.stream.read().await?;
             client2.stream.write(buff.as_slice()).await?;
@@ -55,30 +80,374 @@ mod tests {
 
     #[tokio::test]
     #[serial]
-    async fn test_clients() {
+    async fn test_relay_ping() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let relay_task = spawn_relay(vec![9010]);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        // Go croc checks relays with raw bytes, without any framing
+        let mut stream = tokio::net::TcpStream::connect("localhost:9009")
+            .await
+            .unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut pong = [0u8; 4];
+        stream.read_exact(&mut pong).await.unwrap();
+        assert_eq!(&pong, b"pong");
+        relay_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_bad_shared_secret() {
+        // Refused before anything is connected to
+        for secret in ["12345", "1234-", "123é-tests", "1234é"] {
+            let err = client::RelayClient::connect("localhost:1", "pass123", secret, true, true)
+                .await
+                .err()
+                .unwrap();
+            assert!(
+                matches!(
+                    err.downcast_ref::<client::RelayClientError>(),
+                    Some(client::RelayClientError::BadSharedSecret(_))
+                ),
+                "{}",
+                err
+            );
+        }
+        assert_eq!(
+            client::split_secret("1234-ünïcode").unwrap(),
+            ("1234", "ünïcode")
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_relay_password() {
+        let relay_task = tokio::task::spawn(async {
+            let relay =
+                server::Relay::new("0.0.0.0:9009".to_string(), "secret".to_string(), vec![9010]);
+            relay.start().await.unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        // The data ports check the same password as the main one
+        for address in ["localhost:9009", "localhost:9010"] {
+            let err = client::RelayClient::connect_to_room(address, "pass123", "room")
+                .await
+                .err()
+                .unwrap();
+            assert!(matches!(
+                err.downcast_ref::<client::RelayClientError>(),
+                Some(client::RelayClientError::BadPassword)
+            ));
+            client::RelayClient::connect_to_room(address, "secret", "room")
+                .await
+                .unwrap();
+        }
+        relay_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_relay_limits() {
         let relay_task = tokio::task::spawn(async {
             let relay = server::Relay::new(
                 "0.0.0.0:9009".to_string(),
                 "pass123".to_string(),
                 vec![9010],
-            );
+            )
+            .with_limits(RelayLimits {
+                max_connections: Some(2),
+                max_rooms_per_ip: Some(1),
+                ..Default::default()
+            });
+            relay.start().await.unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let _first = client::RelayClient::connect_to_room("localhost:9009", "pass123", "first")
+            .await
+            .unwrap();
+        let err = client::RelayClient::connect_to_room("localhost:9009", "pass123", "second")
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<client::RelayClientError>(),
+            Some(client::RelayClientError::Refused(_))
+        ));
+        // Joining a room is not opening one
+        let _joined = client::RelayClient::connect_to_room("localhost:9009", "pass123", "first")
+            .await
+            .unwrap();
+        // Both connections of the first room are still open
+        let err = client::RelayClient::connect_to_room("localhost:9010", "pass123", "third")
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<ProtoError>(),
+            Some(ProtoError::Refused(_))
+        ));
+        relay_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_handshake_timeout() {
+        let relay_task = tokio::task::spawn(async {
+            let relay = server::Relay::new(
+                "0.0.0.0:9009".to_string(),
+                "pass123".to_string(),
+                vec![9010],
+            )
+            // The key exchange takes a few seconds in debug builds
+            .with_handshake_timeout(std::time::Duration::from_secs(5));
+            relay.start().await.unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        // Connected and never says anything
+        let mut idle = tokio::net::TcpStream::connect("localhost:9009")
+            .await
+            .unwrap();
+        let mut buffer = [0u8; 1];
+        let closed = tokio::time::timeout(
+            std::time::Duration::from_secs(8),
+            tokio::io::AsyncReadExt::read(&mut idle, &mut buffer),
+        )
+        .await;
+        assert!(matches!(closed, Ok(Ok(0))), "{:?}", closed);
+        // A client in a room is not timed out while it waits
+        let mut waiting =
+            client::RelayClient::connect("localhost:9009", "pass123", "1234-tests", true, true)
+                .await
+                .unwrap();
+        let waited = tokio::time::timeout(
+            std::time::Duration::from_secs(6),
+            waiting.handle_keepalive(),
+        )
+        .await;
+        assert!(waited.is_err(), "{:?}", waited);
+        relay_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_room_quota() {
+        let relay_task = tokio::task::spawn(async {
+            let relay = server::Relay::new(
+                "0.0.0.0:9009".to_string(),
+                "pass123".to_string(),
+                vec![9010],
+            )
+            .with_limits(RelayLimits {
+                max_room_bytes: Some(10_000),
+                ..Default::default()
+            });
+            relay.start().await.unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        async fn sender() -> Result<()> {
+            let mut client = client::RelayClient::connect(
+                "localhost:9009",
+                "pass123",
+                "1234-tests",
+                false,
+                true,
+            )
+            .await?
+            .wait_for_receiver()
+            .await?;
+            client.stream.write(&[7u8; 5000]).await?;
+            client.stream.write(&[7u8; 6000]).await?;
+            // Waits for the relay to close the room
+            client.stream.read().await?;
+            Ok(())
+        }
+        async fn receiver() -> Result<Vec<u8>> {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            let mut client = client::RelayClient::connect(
+                "localhost:9009",
+                "pass123",
+                "1234-tests",
+                false,
+                false,
+            )
+            .await?
+            .connect_to_sender()
+            .await?;
+            let first = client.stream.read().await?;
+            // The second message would go over the quota
+            assert!(client.stream.read().await.is_err());
+            Ok(first)
+        }
+        let (sent, received) = tokio::join!(sender(), receiver());
+        assert!(sent.is_err());
+        assert_eq!(received.unwrap(), vec![7u8; 5000]);
+        relay_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_relay_metrics() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let relay_task = tokio::task::spawn(async {
+            let relay = server::Relay::new(
+                "0.0.0.0:9009".to_string(),
+                "pass123".to_string(),
+                vec![9010],
+            )
+            .with_metrics("127.0.0.1:9015".to_string());
+            relay.start().await.unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        client::RelayClient::connect_to_room("localhost:9009", "wrong", "room")
+            .await
+            .err()
+            .unwrap();
+        let mut garbage = tokio::net::TcpStream::connect("localhost:9009")
+            .await
+            .unwrap();
+        garbage.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        // The relay hangs up on it
+        let _ = garbage.read_to_end(&mut vec![]).await;
+        let _waiting = client::RelayClient::connect_to_room("localhost:9009", "pass123", "room")
+            .await
+            .unwrap();
+
+        let mut stream = tokio::net::TcpStream::connect("127.0.0.1:9015")
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: relay\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in [
+            "croc_relay_rooms 1",
+            "croc_relay_waiting_senders 1",
+            "croc_relay_handshake_failures_total{reason=\"bad_password\"} 1",
+            "croc_relay_handshake_failures_total{reason=\"bad_magic\"} 1",
+            "croc_relay_handshake_failures_total{reason=\"room_full\"} 0",
+        ] {
+            assert!(response.lines().any(|l| l == line), "{} missing", line);
+        }
+        relay_task.abort();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_room_ttl() {
+        let relay_task = tokio::task::spawn(async {
+            let relay = server::Relay::new(
+                "0.0.0.0:9009".to_string(),
+                "pass123".to_string(),
+                vec![9010],
+            )
+            // Longer than a key exchange takes in debug builds
+            .with_room_ttl(std::time::Duration::from_secs(4))
+            .with_metrics("127.0.0.1:9015".to_string());
             relay.start().await.unwrap();
         });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        // Nobody comes to receive
+        let waiting = async {
+            client::RelayClient::connect("localhost:9009", "pass123", "1234-tests", false, true)
+                .await
+                .unwrap()
+                .wait_for_receiver()
+                .await
+                .err()
+                .unwrap()
+        };
+        // Go croc gets the reason the way it gets any other relay error
+        let go_waiting = async {
+            let (mut stream, _) = go_join_room("localhost:9009", "pass123", "go-tests")
+                .await
+                .unwrap();
+            read_skipping_pings(&mut stream).await.unwrap()
+        };
+        let (err, frame) = tokio::join!(waiting, go_waiting);
+        assert_eq!(frame, b"error: room expired");
+        assert!(matches!(
+            err.downcast_ref::<client::RelayClientError>(),
+            Some(client::RelayClientError::RoomExpired)
+        ));
+        // A relay that stays idle is stopped too, and counted as ended
+        let mut first = client::RelayClient::connect_to_room("localhost:9009", "pass123", "idle")
+            .await
+            .unwrap()
+            .start_mpsc_stream()
+            .unwrap();
+        let _second = client::RelayClient::connect_to_room("localhost:9009", "pass123", "idle")
+            .await
+            .unwrap();
+        let closed = tokio::time::timeout(std::time::Duration::from_secs(15), async {
+            // Skips the pings sent before the second one joined
+            while first.read().await.is_ok() {}
+        })
+        .await;
+        assert!(closed.is_ok());
+        let mut stream = tokio::net::TcpStream::connect("127.0.0.1:9015")
+            .await
+            .unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut stream, b"GET /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut response)
+            .await
+            .unwrap();
+        assert!(
+            response
+                .lines()
+                .any(|l| l == "croc_relay_duration_seconds_count 1"),
+            "{}",
+            response
+        );
+        relay_task.abort();
+    }
 
-        async fn sender(original: PathBuf, dest_file: PathBuf) -> Result<()> {
+    #[tokio::test]
+    #[serial]
+    async fn test_metrics_bind_error() {
+        let _taken = tokio::net::TcpListener::bind("127.0.0.1:9015")
+            .await
+            .unwrap();
+        let relay = server::Relay::new(
+            "0.0.0.0:9009".to_string(),
+            "pass123".to_string(),
+            vec![9010],
+        )
+        .with_metrics("127.0.0.1:9015".to_string());
+        let started = tokio::time::timeout(std::time::Duration::from_secs(1), relay.start()).await;
+        assert!(matches!(started, Ok(Err(_))), "{:?}", started);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_clients() {
+        let relay_task = spawn_relay(vec![9010]);
+
+        async fn sender(original: PathBuf) -> Result<()> {
             let default_relay_addr = "localhost:9009";
-            let transferer =
-                client::RelayClient::connect(default_relay_addr, "pass123", "12345", false, true)
-                    .await?;
+            let transferer = client::RelayClient::connect(
+                default_relay_addr,
+                "pass123",
+                "1234-tests",
+                false,
+                true,
+            )
+            .await?;
             let client = transferer.wait_for_receiver().await?;
             debug!("Start sending");
             let a = client
                 .process_client(Some(FilesInformation {
                     files_to_transfare: vec![FileInfo {
                         name: original.file_name().unwrap().to_str().unwrap().to_string(),
-                        remote_folder: dest_file.to_str().unwrap().to_string(),
+                        remote_folder: ".".to_string(),
                         source_folder: original.parent().unwrap().to_str().unwrap().to_string(),
-                        hash: vec![1, 2, 3],
+                        hash: hash_file(&original, HashAlgorithm::Sha256).await?,
                         size: original.metadata().unwrap().len() as i64,
                         modification_time: "2021-01-01".to_string(),
                         is_compressed: false,
@@ -95,18 +464,28 @@ mod tests {
                     sending_text: false,
                     no_compress: true,
                     hash_algorithm: "sha256".to_string(),
+                    compression: "".to_string(),
+                    no_multiplexing: false,
                 }))
                 .await;
             debug!("Returned");
             a?;
             Ok(())
         }
-        async fn receiver() -> Result<()> {
+        async fn receiver(destination: PathBuf) -> Result<()> {
             let default_relay_addr = "localhost:9009";
-            let transferer2: client::RelayClient =
-                client::RelayClient::connect(default_relay_addr, "pass123", "12345", false, false)
-                    .await?;
-            let client2 = transferer2.connect_to_sender().await?;
+            let transferer2: client::RelayClient = client::RelayClient::connect(
+                default_relay_addr,
+                "pass123",
+                "1234-tests",
+                false,
+                false,
+            )
+            .await?;
+            let client2 = transferer2
+                .connect_to_sender()
+                .await?
+                .with_config(unattended().with_base_dir(destination));
             debug!("Start receiving");
             client2.process_client(None).await?;
             Ok(())
@@ -115,10 +494,10 @@ mod tests {
         // rust code:
         let directory = tempfile::tempdir().unwrap();
         let mut original = NamedTempFile::new().unwrap();
-        original.write(b"hello").unwrap();
+        original.write_all(b"hello").unwrap();
         let (_res2, _res3) = tokio::join!(
-            sender(original.path().to_owned(), directory.path().to_owned()),
-            receiver()
+            sender(original.path().to_owned()),
+            receiver(directory.path().to_owned())
         );
         let mut path_to_dst_file = directory.path().to_owned();
         path_to_dst_file.push(original.path().file_name().unwrap().to_str().unwrap());
@@ -126,4 +505,1250 @@ mod tests {
         assert_eq!(str, "hello");
         relay_task.abort();
     }
+
+    /// Receives without asking anything, overwriting existing files.
+    fn unattended() -> Config {
+        Config::default()
+            .with_policy(ReceivePolicy {
+                auto_accept: true,
+                overwrite: OverwriteMode::Overwrite,
+                max_size: None,
+            })
+            .with_interaction(AutoAccept)
+    }
+
+    /// Sends `files` into `destination` without asking anything.
+    async fn transfer(
+        files: FilesInformation,
+        destination: &Path,
+    ) -> (Result<(), SessionError>, Result<(), SessionError>) {
+        transfer_with(files, unattended(), unattended().with_base_dir(destination)).await
+    }
+
+    async fn transfer_with(
+        files: FilesInformation,
+        sender_config: Config,
+        receiver_config: Config,
+    ) -> (Result<(), SessionError>, Result<(), SessionError>) {
+        let (progress, _) = tokio::sync::mpsc::unbounded_channel();
+        transfer_with_progress(files, sender_config, receiver_config, progress, None).await
+    }
+
+    /// Like `transfer_with`, the receiver reports its progress on `progress`
+    /// and the sender on `sender_progress` when given.
+    async fn transfer_with_progress(
+        files: FilesInformation,
+        sender_config: Config,
+        receiver_config: Config,
+        progress: UnboundedSender<ProgressEvent>,
+        sender_progress: Option<UnboundedSender<ProgressEvent>>,
+    ) -> (Result<(), SessionError>, Result<(), SessionError>) {
+        let relay_task = spawn_relay(vec![9010, 9011]);
+        async fn sender(
+            files: FilesInformation,
+            config: Config,
+            progress: Option<UnboundedSender<ProgressEvent>>,
+        ) -> Result<(), SessionError> {
+            let session = client::RelayClient::connect(
+                "localhost:9009",
+                "pass123",
+                "1234-tests",
+                false,
+                true,
+            )
+            .await?
+            .wait_for_receiver()
+            .await?
+            .with_config(config);
+            match progress {
+                Some(progress) => session.with_progress(progress),
+                None => session,
+            }
+            .process_client(Some(files))
+            .await
+        }
+        async fn receiver(
+            config: Config,
+            progress: UnboundedSender<ProgressEvent>,
+        ) -> Result<(), SessionError> {
+            // The sender is the one creating the room
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            // Reach the relay by another name than the sender does
+            client::RelayClient::connect("127.0.0.1:9009", "pass123", "1234-tests", false, false)
+                .await?
+                .connect_to_sender()
+                .await?
+                .with_config(config)
+                .with_progress(progress)
+                .process_client(None)
+                .await
+        }
+        // Give the relay a moment to bind
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let results = tokio::join!(
+            sender(files, sender_config, sender_progress),
+            receiver(receiver_config, progress)
+        );
+        relay_task.abort();
+        results
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_send_folder() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let tree = source.path().join("tree");
+        std::fs::create_dir_all(tree.join("sub")).unwrap();
+        std::fs::create_dir_all(tree.join("empty")).unwrap();
+        std::fs::write(tree.join("a.txt"), "a").unwrap();
+        std::fs::write(tree.join("sub").join("b.txt"), "b").unwrap();
+
+        let files = FilesInformation::from_paths(&[tree], HashAlgorithm::Xxhash)
+            .await
+            .unwrap();
+        assert_eq!(files.total_folders_number, 3);
+        let (sent, received) = transfer(files, destination.path()).await;
+        sent.unwrap();
+        received.unwrap();
+
+        let tree = destination.path().join("tree");
+        assert_eq!(std::fs::read_to_string(tree.join("a.txt")).unwrap(), "a");
+        assert_eq!(
+            std::fs::read_to_string(tree.join("sub").join("b.txt")).unwrap(),
+            "b"
+        );
+        assert!(tree.join("empty").is_dir());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_hash_mismatch() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("a.txt");
+        std::fs::write(&path, "corrupted on the way").unwrap();
+
+        let mut files = FilesInformation::from_paths(&[path], HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+        let file = &mut files.files_to_transfare.as_mut().unwrap()[0];
+        file.hash[0] ^= 0xff;
+
+        let (_, received) = transfer(files, destination.path()).await;
+        let err = received.unwrap_err().to_string();
+        assert!(err.starts_with("Hash of"), "{}", err);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_resume_transfer() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("big.bin");
+        let content: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8 + 1).collect();
+        std::fs::write(&path, &content).unwrap();
+        // A previous transfer wrote only the first chunk
+        let mut partial = vec![0u8; content.len()];
+        partial[..64 * 1024].copy_from_slice(&content[..64 * 1024]);
+        std::fs::write(destination.path().join("big.bin"), &partial).unwrap();
+
+        let files = FilesInformation::from_paths(&[path], HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+
+        let sizes = resumed_transfer(files, destination.path()).await;
+        // Only what the previous transfer did not write is asked for
+        assert_eq!(sizes, vec![(content.len() - 64 * 1024) as u64]);
+        assert_eq!(
+            std::fs::read(destination.path().join("big.bin")).unwrap(),
+            content
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_resume_older_version() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("big.bin");
+        let content: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8 + 1).collect();
+        std::fs::write(&path, &content).unwrap();
+        // Another file of the same size, with a block of zeros
+        let mut older = vec![7u8; content.len()];
+        older[64 * 1024..96 * 1024].fill(0);
+        std::fs::write(destination.path().join("big.bin"), &older).unwrap();
+
+        let files = FilesInformation::from_paths(&[path], HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+
+        let sizes = resumed_transfer(files, destination.path()).await;
+        // The zeros are patched, the hash does not match and the whole file follows
+        assert_eq!(sizes, vec![32 * 1024, content.len() as u64]);
+        assert_eq!(
+            std::fs::read(destination.path().join("big.bin")).unwrap(),
+            content
+        );
+    }
+
+    /// Sends `files` into `destination` and returns the sizes the receiver
+    /// started receiving.
+    async fn resumed_transfer(files: FilesInformation, destination: &Path) -> Vec<u64> {
+        let (progress, mut events) = tokio::sync::mpsc::unbounded_channel();
+        let (sent, received) = transfer_with_progress(
+            files,
+            unattended(),
+            unattended().with_base_dir(destination),
+            progress,
+            None,
+        )
+        .await;
+        sent.unwrap();
+        received.unwrap();
+        let mut sizes = vec![];
+        while let Ok(event) = events.try_recv() {
+            if let ProgressEvent::FileStarted { size, .. } = event {
+                sizes.push(size);
+            }
+        }
+        sizes
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_skip_identical_file() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("a.txt");
+        std::fs::write(&path, "already there").unwrap();
+        std::fs::write(destination.path().join("a.txt"), "already there").unwrap();
+
+        let files =
+            FilesInformation::from_paths(std::slice::from_ref(&path), HashAlgorithm::Xxhash)
+                .await
+                .unwrap();
+        // The sender would fail to open the file if it was requested
+        std::fs::remove_file(path).unwrap();
+
+        let (sent, received) = transfer(files, destination.path()).await;
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(destination.path().join("a.txt")).unwrap(),
+            "already there"
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_sender_fails_mid_file() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("a.txt");
+        std::fs::write(&path, "gone before it is asked for").unwrap();
+        let files =
+            FilesInformation::from_paths(std::slice::from_ref(&path), HashAlgorithm::Xxhash)
+                .await
+                .unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // The receiver already waits for the file's chunks when the sender fails
+        let (sent, received) = tokio::time::timeout(
+            std::time::Duration::from_secs(60),
+            transfer(files, destination.path()),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(sent, Err(SessionError::Io(_))), "{:?}", sent);
+        assert!(
+            matches!(received, Err(SessionError::Remote(_))),
+            "{:?}",
+            received
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_rename_existing_file() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("a.txt");
+        std::fs::write(&path, "new").unwrap();
+        std::fs::write(destination.path().join("a.txt"), "old").unwrap();
+        std::fs::write(destination.path().join("a (1).txt"), "older").unwrap();
+
+        let files = FilesInformation::from_paths(&[path], HashAlgorithm::Xxhash)
+            .await
+            .unwrap();
+
+        let receiver = unattended()
+            .with_policy(ReceivePolicy {
+                auto_accept: true,
+                overwrite: OverwriteMode::Rename,
+                max_size: None,
+            })
+            .with_base_dir(destination.path());
+        let (sent, received) = transfer_with(files, unattended(), receiver).await;
+        sent.unwrap();
+        received.unwrap();
+        let read = |name: &str| std::fs::read_to_string(destination.path().join(name)).unwrap();
+        assert_eq!(read("a.txt"), "old");
+        assert_eq!(read("a (1).txt"), "older");
+        assert_eq!(read("a (2).txt"), "new");
+    }
+
+    /// Accepts every transfer and refuses every overwrite, remembering what it was asked.
+    #[derive(Default, Clone)]
+    struct Scripted {
+        asked: Arc<Mutex<Vec<PathBuf>>>,
+    }
+
+    impl Interaction for Scripted {
+        fn accept_transfer(&self, _files: &FilesInformation) -> Result<bool> {
+            Ok(true)
+        }
+        fn confirm_overwrite(&self, path: &Path) -> Result<bool> {
+            self.asked.lock().unwrap().push(path.to_owned());
+            Ok(false)
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_interaction() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("a.txt");
+        std::fs::write(&path, "new").unwrap();
+        std::fs::write(destination.path().join("a.txt"), "old").unwrap();
+
+        let files = FilesInformation::from_paths(&[path], HashAlgorithm::Xxhash)
+            .await
+            .unwrap();
+
+        let scripted = Scripted::default();
+        let receiver = Config::default()
+            .with_interaction(scripted.clone())
+            .with_base_dir(destination.path());
+        let (sent, received) = transfer_with(files, unattended(), receiver).await;
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(
+            *scripted.asked.lock().unwrap(),
+            vec![destination.path().join("a.txt")]
+        );
+        assert_eq!(
+            std::fs::read_to_string(destination.path().join("a.txt")).unwrap(),
+            "old"
+        );
+    }
+
+    struct Decline;
+
+    impl Interaction for Decline {
+        fn accept_transfer(&self, _files: &FilesInformation) -> Result<bool> {
+            Ok(false)
+        }
+        fn confirm_overwrite(&self, _path: &Path) -> Result<bool> {
+            Ok(false)
+        }
+    }
+
+    async fn refused_transfer(receiver: Config) -> PathBuf {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("a.txt");
+        std::fs::write(&path, "unwanted").unwrap();
+
+        let files = FilesInformation::from_paths(&[path], HashAlgorithm::Xxhash)
+            .await
+            .unwrap();
+
+        let (sent, received) = transfer_with(
+            files,
+            unattended(),
+            receiver.with_base_dir(destination.path()),
+        )
+        .await;
+        assert!(matches!(sent, Err(SessionError::PeerRefused)), "{:?}", sent);
+        assert!(
+            matches!(received, Err(SessionError::Refused)),
+            "{:?}",
+            received
+        );
+        destination.path().join("a.txt")
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_refuse_transfer() {
+        let received = refused_transfer(Config::default().with_interaction(Decline)).await;
+        assert!(!received.exists());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_max_size() {
+        let receiver = unattended().with_policy(ReceivePolicy {
+            auto_accept: true,
+            overwrite: OverwriteMode::Overwrite,
+            max_size: Some(3),
+        });
+        let received = refused_transfer(receiver).await;
+        assert!(!received.exists());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_multiplexing() {
+        let source = tempfile::tempdir().unwrap();
+        let path = source.path().join("big.bin");
+        // Enough chunks to go over both data connections more than once
+        let content: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        for multiplex in [true, false] {
+            let destination = tempfile::tempdir().unwrap();
+            let files =
+                FilesInformation::from_paths(std::slice::from_ref(&path), HashAlgorithm::Sha256)
+                    .await
+                    .unwrap();
+            let (sent, received) = transfer_with(
+                files,
+                unattended().with_multiplex(multiplex),
+                unattended().with_base_dir(destination.path()),
+            )
+            .await;
+            sent.unwrap();
+            received.unwrap();
+            assert_eq!(
+                std::fs::read(destination.path().join("big.bin")).unwrap(),
+                content
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_chunks_striped() {
+        let relay_task = spawn_relay(vec![9010, 9011]);
+        let source = tempfile::tempdir().unwrap();
+        let path = source.path().join("big.bin");
+        let content: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        let files = FilesInformation::from_paths(&[path], HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+
+        let sender = async {
+            client::RelayClient::connect("localhost:9009", "pass123", "1234-tests", true, true)
+                .await?
+                .wait_for_receiver()
+                .await?
+                .process_client(Some(files))
+                .await
+        };
+        // Reads each data room on its own to see where the chunks went
+        let receiver = async {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            let mut stream = client::RelayClient::connect(
+                "localhost:9009",
+                "pass123",
+                "1234-tests",
+                true,
+                false,
+            )
+            .await?
+            .connect_to_sender()
+            .await?
+            .stream;
+            let keys = go_receiver_keys(&mut stream).await?;
+            let session = Some(&keys);
+            assert_eq!(recv_go(&mut stream, session).await?["t"], "fileinfo");
+            let mut rooms = vec![];
+            for (index, port) in [9010, 9011].iter().enumerate() {
+                let address = format!("localhost:{port}");
+                let room = go_data_room("1234-tests", index);
+                let (data, _) = go_join_room(&address, "pass123", &room).await?;
+                // Unlike CrocProto::read, receiving from a channel can be raced
+                rooms.push(MpscCrocProto::from_stream(data.connection)?.into_split().0);
+            }
+            send_go(
+                &mut stream,
+                session,
+                &format!(
+                    r#"{{"t":"recipientready","b":"{}"}}"#,
+                    STANDARD.encode(r#"{"FilesToTransferCurrentNum":0}"#)
+                ),
+            )
+            .await?;
+            let mut received = vec![0u8; content.len()];
+            let mut chunks = [0; 2];
+            let mut amount = 0;
+            while amount < content.len() {
+                let [first, second] = &mut rooms[..] else {
+                    unreachable!()
+                };
+                let (room, frame) = tokio::select! {
+                    frame = first.read() => (0, frame?),
+                    frame = second.read() => (1, frame?),
+                };
+                if frame == [1u8] {
+                    continue;
+                }
+                let (offset, chunk) = go_unchunk(&keys, &frame)?;
+                received[offset as usize..][..chunk.len()].copy_from_slice(&chunk);
+                amount += chunk.len();
+                chunks[room] += 1;
+            }
+            send_go(&mut stream, session, r#"{"t":"close-sender"}"#).await?;
+            assert_eq!(recv_go(&mut stream, session).await?["t"], "close-recipient");
+            send_go(&mut stream, session, r#"{"t":"finished"}"#).await?;
+            assert_eq!(recv_go(&mut stream, session).await?["t"], "finished");
+            assert_eq!(received, content);
+            Ok::<_, anyhow::Error>(chunks)
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let (sent, received) = tokio::join!(sender, receiver);
+        relay_task.abort();
+        sent.unwrap();
+        let chunks = received.unwrap();
+        // 7 chunks, one room after the other
+        assert_eq!(chunks, [4, 3]);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_local_relay() {
+        let relays = [spawn_relay(vec![9010]), spawn_relay_on(9019, vec![9020])];
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("a.txt");
+        std::fs::write(&path, "through the lan").unwrap();
+        let files =
+            FilesInformation::from_paths(std::slice::from_ref(&path), HashAlgorithm::Sha256)
+                .await
+                .unwrap();
+
+        async fn sender(files: FilesInformation) -> Result<(), SessionError> {
+            let public = client::RelayClient::connect(
+                "localhost:9009",
+                "pass123",
+                "1234-tests",
+                false,
+                true,
+            )
+            .await?
+            .with_local_relay(9019);
+            let local =
+                client::RelayClient::connect("localhost:9019", "pass123", "1234-tests", true, true)
+                    .await?;
+            client::wait_for_receiver_on_either(public, local)
+                .await?
+                .process_client(Some(files))
+                .await
+        }
+        async fn receiver(destination: &Path) -> Result<(), SessionError> {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            let session = client::RelayClient::connect(
+                "localhost:9009",
+                "pass123",
+                "1234-tests",
+                false,
+                false,
+            )
+            .await?
+            .connect_to_sender()
+            .await?;
+            // The data connections go to the local relay's multiplex port
+            assert_eq!(session.relay().ports, vec!["9020".to_string()]);
+            session
+                .with_config(unattended().with_base_dir(destination))
+                .process_client(None)
+                .await
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let (sent, received) = tokio::join!(sender(files), receiver(destination.path()));
+        relays.iter().for_each(tokio::task::JoinHandle::abort);
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(destination.path().join("a.txt")).unwrap(),
+            "through the lan"
+        );
+    }
+
+    /// Sends a message the way Go croc builds it: JSON, flate compressed and
+    /// encrypted once there is a session.
+    async fn send_go(
+        stream: &mut CrocProto,
+        session: Option<&EncryptedSession>,
+        envelope: &str,
+    ) -> Result<()> {
+        let data = compress(envelope.as_bytes())?;
+        match session {
+            Some(session) => stream.write(&session.as_encryptor().encrypt(&data)?).await,
+            None => stream.write(&data).await,
+        }
+    }
+
+    async fn recv_go(
+        stream: &mut CrocProto,
+        session: Option<&EncryptedSession>,
+    ) -> Result<serde_json::Value> {
+        let data = stream.read().await?;
+        let data = match session {
+            Some(session) => session.as_encryptor().decrypt(&data)?,
+            None => data,
+        };
+        Ok(serde_json::from_slice(&decompress(&data)?)?)
+    }
+
+    /// Runs a relay on 9009 with `multiplex_ports` until the handle is aborted.
+    fn spawn_relay(multiplex_ports: Vec<u16>) -> tokio::task::JoinHandle<()> {
+        spawn_relay_on(9009, multiplex_ports)
+    }
+
+    /// Like `spawn_relay`, with the relay on `port`.
+    fn spawn_relay_on(port: u16, multiplex_ports: Vec<u16>) -> tokio::task::JoinHandle<()> {
+        tokio::task::spawn(async move {
+            let relay = server::Relay::new(
+                format!("0.0.0.0:{port}"),
+                "pass123".to_string(),
+                multiplex_ports,
+            );
+            relay.start().await.unwrap();
+        })
+    }
+
+    /// Joins `room` on the relay at `address` step by step, the way Go croc's
+    /// `tcp.ConnectToTCPServer` does. Returns the connection and the relay's reply
+    /// to the password.
+    async fn go_join_room(
+        address: &str,
+        password: &str,
+        room: &str,
+    ) -> Result<(CrocProto, String)> {
+        let mut stream = CrocProto::connect(address).await?;
+        // Relays use a fixed weak key, the password is only sent once encrypted
+        let mut key = Pake::new(Role::Sender, None);
+        stream.write(&serde_json::to_vec(&key.pub_pake)?).await?;
+        key.update(serde_json::from_slice(&stream.read().await?)?)?;
+        let salt = [3u8; 8];
+        stream.write(&salt).await?;
+        let relay = AesEncryptor::new(&key.k.context("No relay key")?, Some(salt));
+        stream.write(&relay.encrypt(password.as_bytes())?).await?;
+        let banner = String::from_utf8(relay.decrypt(&stream.read().await?)?)?;
+        if banner == "bad password" {
+            return Ok((stream, banner));
+        }
+        stream.write(&relay.encrypt(room.as_bytes())?).await?;
+        assert_eq!(relay.decrypt(&stream.read().await?)?, b"ok");
+        Ok((stream, banner))
+    }
+
+    /// Go croc's name for data room `index` of a transfer.
+    fn go_data_room(shared_secret: &str, index: usize) -> String {
+        let room = client::room_name(shared_secret).unwrap();
+        format!("{}-{index}", &hex::encode(Sha256::digest(room))[..6])
+    }
+
+    /// Reads the next frame that is not one of the relay's keepalive pings.
+    async fn read_skipping_pings(stream: &mut CrocProto) -> Result<Vec<u8>> {
+        loop {
+            let data = stream.read().await?;
+            if data != [1u8] {
+                return Ok(data);
+            }
+        }
+    }
+
+    /// A chunk as Go croc puts it on a data connection: the offset as 8 little
+    /// endian bytes then the data, flate compressed and encrypted.
+    fn go_chunk(session: &EncryptedSession, offset: u64, data: &[u8]) -> Result<Vec<u8>> {
+        let mut chunk = offset.to_le_bytes().to_vec();
+        chunk.extend_from_slice(data);
+        session.as_encryptor().encrypt(&compress(&chunk)?)
+    }
+
+    /// Splits a chunk made by `go_chunk`.
+    fn go_unchunk(session: &EncryptedSession, frame: &[u8]) -> Result<(u64, Vec<u8>)> {
+        let chunk = decompress(&session.as_encryptor().decrypt(frame)?)?;
+        let (offset, data) = chunk.split_at(8);
+        Ok((u64::from_le_bytes(offset.try_into()?), data.to_vec()))
+    }
+
+    /// Exchanges keys and addresses with a sender the way a Go croc receiver
+    /// does, returns the session keys.
+    async fn go_receiver_keys(stream: &mut CrocProto) -> Result<EncryptedSession> {
+        let mut key = Pake::new(Role::Sender, Some(b"tests"));
+        let pake = format!(
+            r#"{{"t":"pake","b":"{}","b2":"{}"}}"#,
+            STANDARD.encode(serde_json::to_vec(&key.pub_pake)?),
+            STANDARD.encode("siec")
+        );
+        send_go(stream, None, &pake).await?;
+        let reply = recv_go(stream, None).await?;
+        let decode = |field: &str| STANDARD.decode(reply[field].as_str().unwrap_or_default());
+        key.update(serde_json::from_slice(&decode("b")?)?)?;
+        let salt: [u8; 8] = decode("b2")?.as_slice().try_into()?;
+        let keys = EncryptedSession::from_encryptor(AesEncryptor::new(
+            &key.k.context("No session key")?,
+            Some(salt),
+        ));
+        send_go(stream, Some(&keys), r#"{"t":"externalip","m":"127.0.0.1"}"#).await?;
+        assert_eq!(recv_go(stream, Some(&keys)).await?["t"], "externalip");
+        Ok(keys)
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_go_relay_handshake() {
+        let relay_task = spawn_relay(vec![9010]);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let go_client = async {
+            let (_, banner) = go_join_room("localhost:9009", "wrong", "room").await?;
+            assert_eq!(banner, "bad password");
+
+            let (mut first, banner) = go_join_room("localhost:9009", "pass123", "room").await?;
+            // The multiplex ports, then the address the relay sees the client at
+            let (ports, ip) = banner.split_once("|||").context("No ip in the banner")?;
+            assert_eq!(ports, "9010");
+            assert!(ip.parse::<std::net::SocketAddr>().is_ok(), "{}", ip);
+            let (mut second, _) = go_join_room("localhost:9009", "pass123", "room").await?;
+            // The room now relays whatever either side sends
+            second.write(b"handshake").await?;
+            assert_eq!(read_skipping_pings(&mut first).await?, b"handshake");
+            first.write(b"reply").await?;
+            assert_eq!(second.read().await?, b"reply");
+            Ok::<_, anyhow::Error>(())
+        };
+        let result = go_client.await;
+        relay_task.abort();
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_go_sender() {
+        let relay_task = spawn_relay(vec![9010]);
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("go.bin");
+        // Two chunks of Go croc's size, the second one short
+        let content: Vec<u8> = (0..40_000).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        let hash = hash_file(&path, HashAlgorithm::Xxhash).await.unwrap();
+
+        // Plays the sender's side of the session like Go croc v10 does
+        let go_sender = async {
+            let (mut stream, _) = go_join_room(
+                "localhost:9009",
+                "pass123",
+                &client::room_name("1234-tests")?,
+            )
+            .await?;
+            assert_eq!(read_skipping_pings(&mut stream).await?, b"handshake");
+            let pake = recv_go(&mut stream, None).await?;
+            assert_eq!(pake["t"], "pake");
+            let mut key = Pake::new(Role::Reciever, Some(b"tests"));
+            key.update(serde_json::from_slice(
+                &STANDARD.decode(pake["b"].as_str().unwrap_or_default())?,
+            )?)?;
+            let salt = [5u8; 8];
+            let reply = format!(
+                r#"{{"t":"pake","b":"{}","b2":"{}"}}"#,
+                STANDARD.encode(serde_json::to_vec(&key.pub_pake)?),
+                STANDARD.encode(salt)
+            );
+            send_go(&mut stream, None, &reply).await?;
+            let keys = EncryptedSession::from_encryptor(AesEncryptor::new(
+                &key.k.context("No session key")?,
+                Some(salt),
+            ));
+            let session = Some(&keys);
+            // Go joins the data rooms once the keys are exchanged
+            let (mut data, _) =
+                go_join_room("localhost:9010", "pass123", &go_data_room("1234-tests", 0)).await?;
+            assert_eq!(recv_go(&mut stream, session).await?["t"], "externalip");
+            send_go(
+                &mut stream,
+                session,
+                r#"{"t":"externalip","m":"127.0.0.1"}"#,
+            )
+            .await?;
+            let files_info = format!(
+                r#"{{"FilesToTransfer":[{{"n":"go.bin","fr":".","fs":"/tmp","h":"{}","s":{},"m":"2024-01-02T03:04:05Z","c":true,"e":false,"sy":"","md":420,"tf":false}}],"EmptyFoldersToTransfer":null,"TotalNumberFolders":0,"MachineID":"","Ask":false,"SendingText":false,"NoCompress":false,"HashAlgorithm":"xxhash"}}"#,
+                STANDARD.encode(&hash),
+                content.len()
+            );
+            let envelope = format!(
+                r#"{{"t":"fileinfo","b":"{}"}}"#,
+                STANDARD.encode(files_info)
+            );
+            send_go(&mut stream, session, &envelope).await?;
+            let request = recv_go(&mut stream, session).await?;
+            assert_eq!(request["t"], "recipientready");
+            let request: serde_json::Value =
+                serde_json::from_slice(&STANDARD.decode(request["b"].as_str().unwrap())?)?;
+            assert_eq!(request["FilesToTransferCurrentNum"], 0);
+            // Nothing there yet, the whole file is asked for
+            assert!(request["CurrentFileChunkRanges"]
+                .as_array()
+                .is_none_or(Vec::is_empty));
+            // Go sends chunks from several goroutines, they may come in any order
+            for offset in [32768, 0] {
+                let end = content.len().min(offset + 32768);
+                data.write(&go_chunk(&keys, offset as u64, &content[offset..end])?)
+                    .await?;
+            }
+            assert_eq!(recv_go(&mut stream, session).await?["t"], "close-sender");
+            send_go(&mut stream, session, r#"{"t":"close-recipient"}"#).await?;
+            assert_eq!(recv_go(&mut stream, session).await?["t"], "finished");
+            send_go(&mut stream, session, r#"{"t":"finished"}"#).await?;
+            Ok::<_, anyhow::Error>(())
+        };
+        let receiver = async {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            client::RelayClient::connect("localhost:9009", "pass123", "1234-tests", true, false)
+                .await?
+                .connect_to_sender()
+                .await?
+                .with_config(unattended().with_base_dir(destination.path()))
+                .process_client(None)
+                .await
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let (sent, received) = tokio::join!(go_sender, receiver);
+        relay_task.abort();
+        sent.unwrap();
+        received.unwrap();
+        assert_eq!(
+            std::fs::read(destination.path().join("go.bin")).unwrap(),
+            content
+        );
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_go_receiver() {
+        // What a Go croc v10 receiver sends once it has a file, and to end the transfer
+        const GO_CLOSE_SENDER: &str = r#"{"t":"close-sender"}"#;
+        const GO_FINISHED: &str = r#"{"t":"finished"}"#;
+
+        let relay_task = spawn_relay(vec![9010]);
+        let source = tempfile::tempdir().unwrap();
+        let path = source.path().join("a.bin");
+        let content: Vec<u8> = (0..40_000).map(|i| (i % 239) as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+        let files = FilesInformation::from_paths(&[path], HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+
+        let sender = async {
+            client::RelayClient::connect("localhost:9009", "pass123", "1234-tests", true, true)
+                .await?
+                .wait_for_receiver()
+                .await?
+                .process_client(Some(files))
+                .await
+        };
+        // Plays the receiver's side of the session like Go croc does
+        let go_receiver = async {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            let mut stream = client::RelayClient::connect(
+                "localhost:9009",
+                "pass123",
+                "1234-tests",
+                true,
+                false,
+            )
+            .await?
+            .connect_to_sender()
+            .await?
+            .stream;
+            let keys = go_receiver_keys(&mut stream).await?;
+            let session = Some(&keys);
+            let (mut data, _) =
+                go_join_room("localhost:9010", "pass123", &go_data_room("1234-tests", 0)).await?;
+            assert_eq!(recv_go(&mut stream, session).await?["t"], "fileinfo");
+            // Only the second chunk is missing: chunk size, then offset and count
+            let request = format!(
+                r#"{{"t":"recipientready","b":"{}"}}"#,
+                STANDARD.encode(
+                    r#"{"CurrentFileChunkRanges":[32768,32768,1],"FilesToTransferCurrentNum":0,"MachineID":""}"#
+                )
+            );
+            send_go(&mut stream, session, &request).await?;
+            let (offset, chunk) = go_unchunk(&keys, &read_skipping_pings(&mut data).await?)?;
+            assert_eq!(offset, 32768);
+            assert_eq!(chunk, content[32768..]);
+            send_go(&mut stream, session, GO_CLOSE_SENDER).await?;
+            assert_eq!(recv_go(&mut stream, session).await?["t"], "close-recipient");
+            send_go(&mut stream, session, GO_FINISHED).await?;
+            assert_eq!(recv_go(&mut stream, session).await?["t"], "finished");
+            Ok::<_, anyhow::Error>(())
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let (sent, received) = tokio::join!(sender, go_receiver);
+        relay_task.abort();
+        received.unwrap();
+        sent.unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_out_of_order_message() {
+        let relay_task = spawn_relay(vec![9010]);
+        let source = tempfile::tempdir().unwrap();
+        let path = source.path().join("a.txt");
+        std::fs::write(&path, "a").unwrap();
+        let files = FilesInformation::from_paths(&[path], HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+
+        let sender = async {
+            client::RelayClient::connect("localhost:9009", "pass123", "1234-tests", true, true)
+                .await?
+                .wait_for_receiver()
+                .await?
+                .process_client(Some(files))
+                .await
+        };
+        // Offers files before any key exchange
+        let peer = async {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            let mut stream = client::RelayClient::connect(
+                "localhost:9009",
+                "pass123",
+                "1234-tests",
+                true,
+                false,
+            )
+            .await?
+            .connect_to_sender()
+            .await?
+            .stream;
+            send_go(&mut stream, None, r#"{"t":"fileinfo","b":"e30="}"#).await?;
+            recv_go(&mut stream, None).await
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let (sent, reply): (Result<(), SessionError>, Result<serde_json::Value>) =
+            tokio::join!(sender, peer);
+        relay_task.abort();
+        assert!(
+            matches!(
+                sent,
+                Err(SessionError::Protocol {
+                    message: "fileinfo",
+                    ..
+                })
+            ),
+            "{:?}",
+            sent
+        );
+        let reply = reply.unwrap();
+        assert_eq!(reply["t"], "error");
+        assert!(reply["m"]
+            .as_str()
+            .unwrap()
+            .starts_with("Unexpected fileinfo"));
+    }
+    #[tokio::test]
+    #[serial]
+    async fn test_send_text() {
+        let (files, _text_file) = FilesInformation::from_text("some token", HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+        assert!(files.sending_text);
+        let file = &files.files_to_transfare.as_ref().unwrap()[0];
+        assert!(file.temp_file);
+        assert_eq!(file.size, "some token".len() as i64);
+
+        let destination = tempfile::tempdir().unwrap();
+        let (progress, mut events) = tokio::sync::mpsc::unbounded_channel();
+        let (sent, received) = transfer_with_progress(
+            files,
+            unattended(),
+            unattended().with_base_dir(destination.path()),
+            progress,
+            None,
+        )
+        .await;
+        sent.unwrap();
+        received.unwrap();
+        let mut texts = vec![];
+        while let Ok(event) = events.try_recv() {
+            if let ProgressEvent::TextReceived { text } = event {
+                texts.push(text);
+            }
+        }
+        assert_eq!(texts, vec!["some token".to_string()]);
+        // Text is only shown, never written where the files go
+        assert_eq!(std::fs::read_dir(destination.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_send_invalid_text() {
+        // Go croc sends whatever bytes it was given
+        let (mut files, text_file) = FilesInformation::from_text("", HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+        std::fs::write(&text_file, b"caf\xe9").unwrap();
+        let mut file = FileInfo::from_path(&text_file, ".".to_string(), HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+        file.temp_file = true;
+        files.files_to_transfare = Some(vec![file.clone()]);
+        let (progress, mut events) = tokio::sync::mpsc::unbounded_channel();
+        let destination = tempfile::tempdir().unwrap();
+        let (sent, received) = transfer_with_progress(
+            files.clone(),
+            unattended(),
+            unattended().with_base_dir(destination.path()),
+            progress,
+            None,
+        )
+        .await;
+        sent.unwrap();
+        received.unwrap();
+        let mut texts = vec![];
+        while let Ok(event) = events.try_recv() {
+            if let ProgressEvent::TextReceived { text } = event {
+                texts.push(text);
+            }
+        }
+        assert_eq!(texts, vec!["caf\u{fffd}".to_string()]);
+
+        // Text is received in memory as a single file, more are refused
+        files.files_to_transfare = Some(vec![file.clone(), file]);
+        let (_, received) = transfer(files, destination.path()).await;
+        assert!(received.is_err());
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_path_traversal() {
+        let source = tempfile::tempdir().unwrap();
+        let path = source.path().join("a.txt");
+        std::fs::write(&path, "escaped").unwrap();
+        let outside = tempfile::tempdir().unwrap();
+
+        for (remote_folder, name) in [
+            (outside.path().to_str().unwrap().to_string(), "a.txt"),
+            ("../escaped".to_string(), "a.txt"),
+            (".".to_string(), "../a.txt"),
+        ] {
+            let destination = tempfile::tempdir().unwrap();
+            let mut files =
+                FilesInformation::from_paths(std::slice::from_ref(&path), HashAlgorithm::Sha256)
+                    .await
+                    .unwrap();
+            let file = &mut files.files_to_transfare.as_mut().unwrap()[0];
+            file.remote_folder = remote_folder;
+            file.name = name.to_string();
+
+            let (_, received) = transfer(files, destination.path()).await;
+            let err = received.unwrap_err().to_string();
+            assert!(err.starts_with("Something went wrong"), "{}", err);
+            assert!(!outside.path().join("a.txt").exists());
+            assert!(!destination.path().join("escaped").exists());
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_compression() {
+        let source = tempfile::tempdir().unwrap();
+        let path = source.path().join("log.txt");
+        let content = "GET /index.html 200\n".repeat(10 * 1024);
+        std::fs::write(&path, &content).unwrap();
+
+        for compression in [
+            None,
+            Some(CompressionAlgorithm::Flate),
+            Some(CompressionAlgorithm::Zstd),
+        ] {
+            let destination = tempfile::tempdir().unwrap();
+            let files =
+                FilesInformation::from_paths(std::slice::from_ref(&path), HashAlgorithm::Xxhash)
+                    .await
+                    .unwrap()
+                    .with_compression(compression);
+            let file = &files.files_to_transfare.as_ref().unwrap()[0];
+            assert_eq!(file.is_compressed, compression.is_some());
+
+            let (sent, received) = transfer(files, destination.path()).await;
+            sent.unwrap();
+            received.unwrap();
+            assert_eq!(
+                std::fs::read_to_string(destination.path().join("log.txt")).unwrap(),
+                content
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    #[serial]
+    async fn test_file_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let tree = source.path().join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        let script = tree.join("build.sh");
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(&script)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        std::os::unix::fs::symlink("build.sh", tree.join("link")).unwrap();
+
+        let files = FilesInformation::from_paths(&[tree], HashAlgorithm::Sha256)
+            .await
+            .unwrap();
+        let (sent, received) = transfer(files, destination.path()).await;
+        sent.unwrap();
+        received.unwrap();
+
+        let tree = destination.path().join("tree");
+        let metadata = std::fs::metadata(tree.join("build.sh")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o755);
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert_eq!(
+            std::fs::read_link(tree.join("link")).unwrap(),
+            PathBuf::from("build.sh")
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    #[serial]
+    async fn test_symlinks_stay_inside() {
+        let source = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let secret = outside.path().join("secret");
+        std::fs::write(&secret, "secret").unwrap();
+
+        // Links the sender has may not point out of the destination
+        let tree = source.path().join("tree");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("target"), "target").unwrap();
+        std::os::unix::fs::symlink("target", tree.join("link")).unwrap();
+        for target in [secret.to_str().unwrap(), "../../secret", "../.."] {
+            let destination = tempfile::tempdir().unwrap();
+            let mut files =
+                FilesInformation::from_paths(std::slice::from_ref(&tree), HashAlgorithm::Sha256)
+                    .await
+                    .unwrap();
+            for file in files.files_to_transfare.iter_mut().flatten() {
+                if file.name == "link" {
+                    file.symlink = target.to_string();
+                }
+            }
+            let (_, received) = transfer(files, destination.path()).await;
+            let err = received.unwrap_err().to_string();
+            assert!(err.starts_with("Something went wrong"), "{}", err);
+            assert!(std::fs::symlink_metadata(destination.path().join("tree/link")).is_err());
+        }
+
+        // Links at the destination are replaced and never written through,
+        // whether the file is resumed or received whole
+        for content in ["terces", "received"] {
+            let path = source.path().join("a.txt");
+            std::fs::write(&path, content).unwrap();
+            let destination = tempfile::tempdir().unwrap();
+            std::os::unix::fs::symlink(&secret, destination.path().join("a.txt")).unwrap();
+            let files =
+                FilesInformation::from_paths(std::slice::from_ref(&path), HashAlgorithm::Sha256)
+                    .await
+                    .unwrap();
+            let (sent, received) = transfer(files, destination.path()).await;
+            sent.unwrap();
+            received.unwrap();
+            let received = destination.path().join("a.txt");
+            assert!(std::fs::symlink_metadata(&received).unwrap().is_file());
+            assert_eq!(std::fs::read_to_string(received).unwrap(), content);
+            assert_eq!(std::fs::read_to_string(&secret).unwrap(), "secret");
+        }
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn test_progress_events() {
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let path = source.path().join("big.bin");
+        let size = 100 * 1024;
+        std::fs::write(&path, vec![7u8; size]).unwrap();
+        let files =
+            FilesInformation::from_paths(std::slice::from_ref(&path), HashAlgorithm::Sha256)
+                .await
+                .unwrap();
+
+        let (sender_progress, mut sender_events) = tokio::sync::mpsc::unbounded_channel();
+        let (receiver_progress, mut receiver_events) = tokio::sync::mpsc::unbounded_channel();
+        let (sent, received) = transfer_with_progress(
+            files,
+            unattended(),
+            unattended().with_base_dir(destination.path()),
+            receiver_progress,
+            Some(sender_progress),
+        )
+        .await;
+        sent.unwrap();
+        received.unwrap();
+
+        let file_sent: fn(&ProgressEvent) -> bool =
+            |event| matches!(event, ProgressEvent::FileSent { .. });
+        let file_verified: fn(&ProgressEvent) -> bool =
+            |event| matches!(event, ProgressEvent::FileVerified { .. });
+        for (events, is_file_done) in [
+            (&mut sender_events, file_sent),
+            (&mut receiver_events, file_verified),
+        ] {
+            let mut received = vec![];
+            while let Ok(event) = events.try_recv() {
+                received.push(event);
+            }
+            assert_eq!(
+                received.first(),
+                Some(&ProgressEvent::TransferStarted {
+                    files: 1,
+                    bytes: size as u64
+                })
+            );
+            assert_eq!(
+                received.get(1),
+                Some(&ProgressEvent::FileStarted {
+                    index: 0,
+                    name: "big.bin".to_string(),
+                    size: size as u64
+                })
+            );
+            let bytes: Vec<_> = received
+                .iter()
+                .filter_map(|event| match event {
+                    ProgressEvent::Bytes { done, total, .. } => Some((*done, *total)),
+                    _ => None,
+                })
+                .collect();
+            assert!(bytes.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            assert_eq!(bytes.last(), Some(&(size as u64, size as u64)));
+            assert!(is_file_done(&received[received.len() - 2]));
+            assert!(matches!(
+                received.last(),
+                Some(ProgressEvent::TransferFinished { bytes, .. }) if *bytes == size as u64
+            ));
+        }
+    }
 }
@@ -3,96 +3,192 @@ use chrono::{DateTime, Utc};
 use std::{
     borrow::BorrowMut,
     collections::HashMap,
-    sync::Arc,
+    fmt,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::tcp::{ReadHalf, WriteHalf},
-    sync::Mutex,
+    sync::{Mutex, OwnedSemaphorePermit},
     task::JoinHandle,
     try_join,
 };
 
-use crate::proto::{AsyncCrocWrite, CrocProto, EncryptedSession};
+use crate::{
+    proto::{AsyncCrocWrite, CrocProto, EncryptedSession, ProtoError, ERROR_PREFIX},
+    relay::{
+        limits::{LimitError, Limiter, RelayLimits, RoomPermit, Throttle},
+        metrics::{self, HandshakeFailure, Metrics},
+    },
+};
 use rust_pake::pake::Role;
+pub type Rooms = Arc<Mutex<HashMap<String, Arc<Mutex<Room>>>>>;
+
+// Like Go croc, rooms are dropped after three hours
+const DEFAULT_ROOM_TTL: Duration = Duration::from_secs(3 * 60 * 60);
+// Checking more often than this is pointless with long TTLs
+const MAX_REAP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const MIN_REAP_INTERVAL: Duration = Duration::from_millis(100);
+// Go croc clients are done with the relay handshake in a few round trips
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+// Why a client whose room was evicted gets an error frame in place of a ping
+pub const ROOM_EXPIRED: &str = "room expired";
+
 pub struct Room {
     first: Option<CrocProto>,
     second: Option<CrocProto>,
     handle: Option<JoinHandle<()>>,
     opened: DateTime<Utc>,
+    // Milliseconds timestamp of the last bytes relayed in this room
+    last_active: Arc<AtomicI64>,
+    // Counts the room against its opener until it is dropped
+    _permit: RoomPermit,
+    // The connection slots of the clients in the room, kept while it relays
+    connections: Vec<OwnedSemaphorePermit>,
 }
 impl Room {
+    #![allow(dead_code)]
+    fn new(first: CrocProto, permit: RoomPermit, connection: Option<OwnedSemaphorePermit>) -> Room {
+        let opened: DateTime<Utc> = SystemTime::now().into();
+        Room {
+            first: Some(first),
+            second: None,
+            handle: None,
+            opened,
+            last_active: Arc::new(AtomicI64::new(opened.timestamp_millis())),
+            _permit: permit,
+            connections: connection.into_iter().collect(),
+        }
+    }
     pub fn is_full(&self) -> bool {
         (self.first.is_some() && self.second.is_some()) || self.is_running()
     }
+    /// The sender is there and the receiver has not come yet.
+    pub fn is_waiting(&self) -> bool {
+        self.first.is_some() && self.second.is_none()
+    }
     pub fn is_running(&self) -> bool {
         self.handle
             .as_ref()
-            .map_or(false, |handle| !handle.is_finished())
+            .is_some_and(|handle| !handle.is_finished())
     }
     pub fn stop(&mut self) {
-        self.handle.as_mut().map(|handle| handle.abort());
+        if let Some(handle) = self.handle.as_mut() {
+            handle.abort()
+        }
+    }
+    /// A waiting room is stale once it is older than `ttl`, a relaying one once
+    /// nothing went through it for `ttl`.
+    pub fn is_stale(&self, ttl: Duration) -> bool {
+        let since = if self.is_running() {
+            self.last_active.load(Ordering::Relaxed)
+        } else {
+            self.opened.timestamp_millis()
+        };
+        Utc::now().timestamp_millis() - since > ttl.as_millis() as i64
     }
 }
 
-async fn handle(
+/// Everything a client does before it is in a room: answers pings, and runs
+/// the key exchange and the room negotiation. Returns the room joined, if any.
+async fn handshake(
     client: tokio::net::TcpStream,
     relay_password: String,
     multiplex_ports: Vec<u16>,
-    mut rooms: Arc<Mutex<HashMap<String, Arc<Mutex<Room>>>>>,
-) -> Result<()> {
+    mut rooms: Rooms,
+    limiter: &Limiter,
+    connection: Option<OwnedSemaphorePermit>,
+    metrics: &Metrics,
+) -> Result<Option<String>> {
     let mut session = CrocProto::from_stream(client);
     let mut peeked_bytes = [0u8; 4];
 
     session.peek(&mut peeked_bytes).await?;
     if &peeked_bytes == b"ping" {
         debug!("Got ping");
-        session.write(b"pong").await?;
-        return Ok(());
+        // Go croc pings the relay with raw bytes, not a croc frame
+        session.connection.write_all(b"pong").await?;
+        return Ok(None);
+    }
+    if let Err(err) = limiter.handshake(session.connection.peer_addr()?.ip()) {
+        refuse(session, err, metrics).await?;
+        return Ok(None);
     }
-    let sym_key = session.negotiate_symmetric_key(Role::Reciever).await?;
-    let room = negotiate_info(
+    let sym_key = session
+        .negotiate_symmetric_key(Role::Reciever)
+        .await
+        .inspect_err(|err| count_failure(metrics, err))?;
+    let room_name = negotiate_info(
         session,
         &sym_key,
         &relay_password,
         multiplex_ports,
         rooms.borrow_mut(),
+        limiter,
+        connection,
     )
-    .await?;
-    if let Some(room_name) = room {
-        let room = {
-            let mut rooms = rooms.lock().await;
-            rooms.get_mut(&room_name).map(|room| room.clone())
-        };
-        if let Some(room) = room {
-            // We do not need to lock rooms anymore.
-            let mut room_guard = room.lock().await;
-            if room_guard.is_full() {
-                let receiver = room_guard.second.take().unwrap().connection;
-                let sender = room_guard.first.take().unwrap().connection;
-                let result = relay(receiver, sender).await;
-                room_guard.first = None;
-                room_guard.second = None;
-                let mut rooms = rooms.lock().await;
-                rooms.remove(&room_name);
-                debug!("RELAY ENDED: {result:?}");
-            } else {
-                drop(room_guard);
-                // SAFTY: if a keepalive is sent, it will for sure
-                // be sent before the relay start and will not be sent after
-                // (because after the relay is establish it takes the room lock and never
-                // releases it)
-                do_keepalive(rooms, room_name).await?
-            }
+    .await
+    .inspect_err(|err| count_failure(metrics, err))?;
+    Ok(Some(room_name))
+}
+
+/// Starts relaying once both clients are in the room, keeps the first one
+/// alive until then.
+async fn enter_room(
+    rooms: Rooms,
+    room_name: String,
+    limiter: &Limiter,
+    metrics: Arc<Metrics>,
+) -> Result<()> {
+    let room = {
+        let mut rooms = rooms.lock().await;
+        rooms.get_mut(&room_name).map(|room| room.clone())
+    };
+    if let Some(room) = room {
+        // We do not need to lock rooms anymore.
+        let mut room_guard = room.lock().await;
+        if room_guard.first.is_some() && room_guard.second.is_some() {
+            let receiver = room_guard.second.take().unwrap().connection;
+            let sender = room_guard.first.take().unwrap().connection;
+            let bridge = Bridge {
+                last_active: room_guard.last_active.clone(),
+                throttle: limiter.throttle(&[receiver.peer_addr()?.ip(), sender.peer_addr()?.ip()]),
+                metrics,
+            };
+            // The room stays full while the handle runs
+            room_guard.handle = Some(relay(
+                receiver,
+                sender,
+                bridge,
+                rooms.clone(),
+                room_name,
+                room.clone(),
+            ));
+        } else {
+            drop(room_guard);
+            // SAFTY: if a keepalive is sent, it will for sure
+            // be sent before the relay start and will not be sent after
+            // (because after the relay is establish it takes the room lock and never
+            // releases it)
+            do_keepalive(rooms, room_name).await?
         }
     }
     Ok(())
 }
-async fn do_keepalive(
-    rooms: Arc<Mutex<HashMap<String, Arc<Mutex<Room>>>>>,
-    room_name: String,
-) -> Result<()> {
+
+/// Counts the handshakes that failed for a reason the metrics know about.
+fn count_failure(metrics: &Metrics, err: &anyhow::Error) {
+    if let Some(failure) = err.downcast_ref::<HandshakeFailure>() {
+        metrics.handshake_failed(*failure)
+    } else if let Some(ProtoError::BadMagic(_)) = err.downcast_ref::<ProtoError>() {
+        metrics.handshake_failed(HandshakeFailure::BadMagic)
+    }
+}
+async fn do_keepalive(rooms: Rooms, room_name: String) -> Result<()> {
     debug!("Starting keepalive");
     let room = {
         let mut rooms = rooms.lock().await;
@@ -124,24 +220,106 @@ async fn do_keepalive(
     }
     Ok(())
 }
-fn relay(first: tokio::net::TcpStream, second: tokio::net::TcpStream) -> JoinHandle<Result<()>> {
+fn relay(
+    receiver: tokio::net::TcpStream,
+    sender: tokio::net::TcpStream,
+    bridge: Bridge,
+    rooms: Rooms,
+    room_name: String,
+    room: Arc<Mutex<Room>>,
+) -> JoinHandle<()> {
     debug!("Relaying");
-    tokio::spawn(bridge_sockets(first, second))
+    tokio::spawn(async move {
+        // Also counts the relays the reaper stops
+        let _timer = RelayTimer {
+            metrics: bridge.metrics.clone(),
+            started: std::time::Instant::now(),
+        };
+        match bridge_sockets(receiver, sender, bridge).await {
+            Err(err) if err.is::<LimitError>() => info!("Closed room {room_name}: {err}"),
+            result => debug!("RELAY ENDED: {result:?}"),
+        }
+        let mut rooms = rooms.lock().await;
+        // The reaper may have evicted it and someone else opened a room by that name
+        if rooms
+            .get(&room_name)
+            .is_some_and(|current| Arc::ptr_eq(current, &room))
+        {
+            rooms.remove(&room_name);
+        }
+    })
+}
+
+/// Records how long a relay ran once its task ends, aborted or not.
+struct RelayTimer {
+    metrics: Arc<Metrics>,
+    started: std::time::Instant,
+}
+
+impl Drop for RelayTimer {
+    fn drop(&mut self) {
+        self.metrics.relay_ended(self.started.elapsed());
+    }
+}
+
+/// Evicts the stale rooms (see `Room::is_stale`), letting whoever still waits
+/// in them know and stopping their relays.
+async fn reap_rooms(rooms: &Rooms, ttl: Duration) {
+    let stale = {
+        let mut rooms = rooms.lock().await;
+        let mut stale = vec![];
+        rooms.retain(|room_name, room| match room.try_lock() {
+            // A locked room is in use right now
+            Ok(room_guard) if room_guard.is_stale(ttl) => {
+                debug!("Evicting stale room {room_name}");
+                stale.push(room.clone());
+                false
+            }
+            _ => true,
+        });
+        stale
+    };
+    for room in stale {
+        let mut room = room.lock().await;
+        room.stop();
+        let expired = error_frame(ROOM_EXPIRED);
+        for mut client in room.first.take().into_iter().chain(room.second.take()) {
+            // A client that does not read is not waited for
+            match tokio::time::timeout(Duration::from_secs(1), client.write(&expired)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => debug!("Could not tell the client its room expired: {err}"),
+                Err(_) => debug!("Timed out telling the client its room expired"),
+            }
+        }
+    }
+}
+
+async fn reap_rooms_task(rooms: Rooms, ttl: Duration) {
+    // A zero period would panic
+    let mut interval = tokio::time::interval(ttl.clamp(MIN_REAP_INTERVAL, MAX_REAP_INTERVAL));
+    loop {
+        interval.tick().await;
+        reap_rooms(&rooms, ttl).await;
+    }
 }
 async fn negotiate_info(
     mut session: CrocProto,
     sym_key: &[u8; 32],
     relay_password: &str,
     multiplex_ports: Vec<u16>,
-    rooms: &mut Arc<Mutex<HashMap<String, Arc<Mutex<Room>>>>>,
-) -> Result<Option<String>> {
+    rooms: &mut Rooms,
+    limiter: &Limiter,
+    connection: Option<OwnedSemaphorePermit>,
+) -> Result<String> {
     let enc = EncryptedSession::new(&mut session, sym_key, Role::Reciever).await?;
     let password = String::from_utf8(enc.read(&mut session).await?)?;
     if password != relay_password.trim() {
         debug!("Bad password {password}");
-        enc.write(&mut session, b"bad password").await?
+        enc.write(&mut session, b"bad password").await?;
+        // Dropping the session closes the connection
+        return Err(HandshakeFailure::BadPassword.into());
     }
-    let message = if multiplex_ports.len() == 0 {
+    let message = if multiplex_ports.is_empty() {
         "ok".to_string()
     } else {
         multiplex_ports
@@ -162,62 +340,102 @@ async fn negotiate_info(
             if room_guard.is_full() {
                 debug!("Room is full");
                 enc.write(&mut session, b"room full").await?;
-                return Ok(None);
+                Err(HandshakeFailure::RoomFull.into())
             } else {
                 debug!("Adding receiver to {room_name}");
                 enc.write(&mut session, b"ok").await?;
                 room_guard.second = Some(session);
-                Ok(Some(room_name))
+                room_guard.connections.extend(connection);
+                Ok(room_name)
             }
         }
         None => {
+            let permit = match limiter.room(session.connection.peer_addr()?.ip(), guard.len()) {
+                Ok(permit) => permit,
+                Err(err) => {
+                    debug!("Not creating room {room_name}: {err}");
+                    enc.write(&mut session, &error_frame(&err)).await?;
+                    return Err(HandshakeFailure::OverLimit.into());
+                }
+            };
             debug!("Creating room {room_name} and adding the sender to it");
             enc.write(&mut session, b"ok").await?;
             guard.insert(
                 room_name.clone(),
-                Arc::new(Mutex::new(Room {
-                    first: Some(session),
-                    second: None,
-                    opened: SystemTime::now().into(),
-                    handle: None,
-                })),
+                Arc::new(Mutex::new(Room::new(session, permit, connection))),
             );
-            Ok(Some(room_name))
+            Ok(room_name)
         }
     }
 }
+fn error_frame(reason: impl fmt::Display) -> Vec<u8> {
+    [ERROR_PREFIX, reason.to_string().as_bytes()].concat()
+}
+
+/// Tells a client over a limit why it is refused and closes its connection.
+/// Nothing more is read from it, a client that keeps sending may miss the frame.
+async fn refuse(mut session: CrocProto, err: LimitError, metrics: &Metrics) -> Result<()> {
+    debug!("Refusing {:?}: {err}", session.connection.peer_addr());
+    metrics.handshake_failed(HandshakeFailure::OverLimit);
+    session.write(&error_frame(&err)).await?;
+    session.connection.shutdown().await?;
+    Ok(())
+}
+
 #[derive(Clone)]
 pub struct Relay {
-    rooms: Arc<Mutex<HashMap<String, Arc<Mutex<Room>>>>>,
+    rooms: Rooms,
     bind_address: String,
     password: String,
     multiplex_ports: Vec<u16>,
+    room_ttl: Duration,
+    handshake_timeout: Duration,
+    limits: RelayLimits,
+    metrics_address: Option<String>,
+}
+
+/// What a relaying room keeps track of while bytes go through it.
+struct Bridge {
+    last_active: Arc<AtomicI64>,
+    throttle: Throttle,
+    metrics: Arc<Metrics>,
 }
 
 // TODO: Add error handling
 async fn asymmetric_bridge_sockets<'a>(
     mut from: ReadHalf<'a>,
     mut to_s: WriteHalf<'a>,
+    bridge: &Bridge,
+    relayed: &AtomicU64,
 ) -> Result<()> {
     loop {
         let mut buffer_a = [0u8; 1024];
         let amount = from.read(&mut buffer_a).await?;
         if amount == 0 {
+            // Let the other side know this one is gone
+            to_s.shutdown().await?;
             return Ok(());
         }
-        to_s.write_all(&mut buffer_a[..amount]).await?;
+        bridge.throttle.take(amount).await?;
+        to_s.write_all(&buffer_a[..amount]).await?;
+        relayed.fetch_add(amount as u64, Ordering::Relaxed);
+        bridge
+            .last_active
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
     }
 }
 async fn bridge_sockets(
-    mut stream_a: tokio::net::TcpStream,
-    mut stream_b: tokio::net::TcpStream,
+    mut receiver: tokio::net::TcpStream,
+    mut sender: tokio::net::TcpStream,
+    bridge: Bridge,
 ) -> Result<()> {
     // TODO: use tokio::io::copy
     // TODO: pass streams using channel
-    let (a_r, a_w) = stream_a.split();
-    let (b_r, b_w) = stream_b.split();
-    let a_to_b = asymmetric_bridge_sockets(a_r, b_w);
-    let b_to_a = asymmetric_bridge_sockets(b_r, a_w);
+    let (a_r, a_w) = receiver.split();
+    let (b_r, b_w) = sender.split();
+    let metrics = &bridge.metrics;
+    let a_to_b = asymmetric_bridge_sockets(a_r, b_w, &bridge, &metrics.receiver_to_sender);
+    let b_to_a = asymmetric_bridge_sockets(b_r, a_w, &bridge, &metrics.sender_to_receiver);
     try_join!(a_to_b, b_to_a)?;
     Ok(())
 }
@@ -225,19 +443,51 @@ async fn bridge_sockets(
 pub async fn run_instance(
     password: String,
     multiplex_ports: Vec<u16>,
-    rooms: Arc<Mutex<HashMap<String, Arc<Mutex<Room>>>>>,
+    rooms: Rooms,
     bind_address: std::net::SocketAddr,
+    limiter: Arc<Limiter>,
+    metrics: Arc<Metrics>,
+    handshake_timeout: Duration,
 ) -> Result<()> {
     let listener = tokio::net::TcpListener::bind(&bind_address).await?;
     loop {
         let (stream, addr) = listener.accept().await?;
         debug!("Got client {addr}");
-        tokio::spawn(handle(
-            stream,
+        let permit = limiter.connection();
+        let (password, multiplex_ports, rooms, limiter, metrics) = (
             password.clone(),
             multiplex_ports.clone(),
             rooms.clone(),
-        ));
+            limiter.clone(),
+            metrics.clone(),
+        );
+        tokio::spawn(async move {
+            // The room holds on to it while the client is in one
+            let connection = match permit {
+                Ok(connection) => connection,
+                Err(err) => return refuse(CrocProto::from_stream(stream), err, &metrics).await,
+            };
+            // Clients that stall before they are in a room don't get to hold on to it
+            let handshake = handshake(
+                stream,
+                password,
+                multiplex_ports,
+                rooms.clone(),
+                &limiter,
+                connection,
+                &metrics,
+            );
+            let room_name = match tokio::time::timeout(handshake_timeout, handshake).await {
+                Ok(Ok(Some(room_name))) => room_name,
+                Ok(result) => return result.map(|_| ()),
+                Err(_) => {
+                    debug!("Handshake of {addr} timed out");
+                    metrics.handshake_failed(HandshakeFailure::TimedOut);
+                    return Ok(());
+                }
+            };
+            enter_room(rooms, room_name, &limiter, metrics).await
+        });
     }
 }
 
@@ -248,11 +498,47 @@ impl Relay {
             bind_address,
             password,
             multiplex_ports,
+            room_ttl: DEFAULT_ROOM_TTL,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            limits: RelayLimits::default(),
+            metrics_address: None,
         }
     }
+    /// How long a room may wait for its receiver, or relay nothing, before it is evicted.
+    pub fn with_room_ttl(mut self, room_ttl: Duration) -> Self {
+        self.room_ttl = room_ttl;
+        self
+    }
+    /// How long a client may take to get into a room once connected.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+    pub fn with_limits(mut self, limits: RelayLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+    /// Serves Prometheus metrics over HTTP at `address`.
+    pub fn with_metrics(mut self, address: String) -> Self {
+        self.metrics_address = Some(address);
+        self
+    }
     pub async fn start(self) -> Result<()> {
-        // TODO: Create a delete old room task
         debug!("Starting relay");
+        // Shared by all listeners, the limits are for the whole relay
+        let limiter = Arc::new(Limiter::new(self.limits));
+        let metrics = Arc::new(Metrics::default());
+        if let Some(address) = &self.metrics_address {
+            debug!("Serving metrics on {address}");
+            // Bound here so a bad address stops the relay instead of a lost task
+            let listener = tokio::net::TcpListener::bind(address).await?;
+            tokio::spawn(metrics::serve(
+                listener,
+                metrics.clone(),
+                self.rooms.clone(),
+            ));
+        }
+        tokio::spawn(reap_rooms_task(self.rooms.clone(), self.room_ttl));
         debug!("Creating file relay sockets");
         let bind_ip = self.bind_address.parse::<std::net::SocketAddr>()?.ip();
         for address in self
@@ -261,10 +547,13 @@ impl Relay {
             .map(|port| std::net::SocketAddr::new(bind_ip, *port))
         {
             tokio::spawn(run_instance(
-                "pass123".to_string(),
+                self.password.clone(),
                 self.multiplex_ports.clone(),
                 self.rooms.clone(),
                 address,
+                limiter.clone(),
+                metrics.clone(),
+                self.handshake_timeout,
             ));
         }
 
@@ -274,6 +563,10 @@ impl Relay {
             self.multiplex_ports,
             self.rooms,
             self.bind_address.parse()?,
-        ).await
+            limiter,
+            metrics,
+            self.handshake_timeout,
+        )
+        .await
     }
 }